@@ -1,8 +1,15 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use futures::executor::block_on;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 
+use hirofa_utils::auto_id_map::AutoIdMap;
+use hirofa_utils::cache::{Cache, CacheIFace};
+use hirofa_utils::concurrent_auto_id_map::ConcurrentAutoIdMap;
 use hirofa_utils::eventloop::EventLoop;
 use hirofa_utils::resolvable_future::ResolvableFuture;
+use hirofa_utils::shared_cache::SharedCache;
 use hirofa_utils::task_manager::TaskManager;
 
 fn test_eventloop_exe() {
@@ -15,6 +22,24 @@ fn test_eventloop_exe() {
     }
 }
 
+fn test_eventloop_add_void(event_loop: &EventLoop) {
+    for x in 0..10000 {
+        event_loop.add_void(move || {
+            black_box(x);
+        });
+    }
+    event_loop.exe(|| {});
+}
+
+fn test_eventloop_add_batch(event_loop: &EventLoop) {
+    event_loop.add_batch((0..10000).map(|x| {
+        move || {
+            black_box(x);
+        }
+    }));
+    event_loop.exe(|| {});
+}
+
 fn test_res_fut() {
     let tm = TaskManager::new(4);
 
@@ -30,9 +55,138 @@ fn test_res_fut() {
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("eventLoop.exe", |b| b.iter(|| test_eventloop_exe()));
-    c.bench_function("test_res_fut", |b| b.iter(|| test_res_fut()));
+    c.bench_function("eventLoop.exe", |b| b.iter(test_eventloop_exe));
+    c.bench_function("test_res_fut", |b| b.iter(test_res_fut));
+
+    let event_loop = EventLoop::new();
+    let mut group = c.benchmark_group("eventLoop 10k tasks");
+    group.throughput(Throughput::Elements(10000));
+    group.bench_function("add_void", |b| {
+        b.iter(|| test_eventloop_add_void(&event_loop))
+    });
+    group.bench_function("add_batch", |b| {
+        b.iter(|| test_eventloop_add_batch(&event_loop))
+    });
+    group.finish();
+}
+
+pub fn auto_id_map_benchmark(c: &mut Criterion) {
+    let max_size = 10000;
+    let mut group = c.benchmark_group("AutoIdMap insert+remove");
+    for fill in [50, 90, 99] {
+        let mut map = AutoIdMap::new_with_max_size(max_size);
+        for x in 0..(max_size * fill / 100) {
+            map.insert(x);
+        }
+        group.bench_function(format!("{fill}% full"), |b| {
+            b.iter(|| {
+                let id = map.insert(black_box(1));
+                map.remove(&id);
+            })
+        });
+    }
+    group.finish();
+}
+
+// run insert, get and remove from several threads at once
+fn contended<I: Fn(usize) -> usize + Sync, G: Fn(&usize) + Sync, R: Fn(&usize) + Sync>(
+    insert: I,
+    get: G,
+    remove: R,
+) {
+    std::thread::scope(|scope| {
+        for _t in 0..4 {
+            scope.spawn(|| {
+                for x in 0..1000 {
+                    let id = insert(x);
+                    get(&id);
+                    remove(&id);
+                }
+            });
+        }
+    });
+}
+
+pub fn concurrent_auto_id_map_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("AutoIdMap 4 threads");
+    let map = Mutex::new(AutoIdMap::new());
+    group.bench_function("Mutex<AutoIdMap>", |b| {
+        b.iter(|| {
+            contended(
+                |x| map.lock().insert(Arc::new(x)),
+                |id| {
+                    black_box(map.lock().get(id).cloned());
+                },
+                |id| {
+                    map.lock().remove(id);
+                },
+            )
+        })
+    });
+    let map = ConcurrentAutoIdMap::new();
+    group.bench_function("ConcurrentAutoIdMap", |b| {
+        b.iter(|| {
+            contended(
+                |x| map.insert(x),
+                |id| {
+                    black_box(map.get(id));
+                },
+                |id| {
+                    map.remove(id);
+                },
+            )
+        })
+    });
+    group.finish();
+}
+
+fn read_heavy<G: Fn(&usize) + Sync>(get: G) {
+    std::thread::scope(|scope| {
+        for t in 0..4 {
+            let get = &get;
+            scope.spawn(move || {
+                for x in 0..1000 {
+                    get(&((x * 7 + t) % 1000));
+                }
+            });
+        }
+    });
+}
+
+pub fn shared_cache_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Cache 4 threads");
+    let cache = Mutex::new(Cache::new(
+        |key: &usize| Some(Arc::new(*key)),
+        Duration::from_secs(60),
+        1000,
+    ));
+    group.bench_function("Mutex<Cache>", |b| {
+        b.iter(|| {
+            read_heavy(|key| {
+                black_box(cache.lock().get(key).cloned());
+            })
+        })
+    });
+    let cache = SharedCache::new(
+        |key: &usize| Some(Arc::new(*key)),
+        Duration::from_secs(60),
+        1000,
+    );
+    group.bench_function("SharedCache", |b| {
+        b.iter(|| {
+            read_heavy(|key| {
+                black_box(cache.get(key));
+            })
+        })
+    });
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    auto_id_map_benchmark,
+    concurrent_auto_id_map_benchmark,
+    shared_cache_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,113 @@
+//! replays synthetic access traces against every CachePolicy and reports the hit ratios
+//!
+//! run with `cargo bench --bench cache_policies`
+
+use hirofa_utils::cache::{Cache, CacheIFace, CachePolicy};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const TRACE_LEN: usize = 200_000;
+const KEY_SPACE: usize = 10_000;
+
+/// samples keys 0..n where key k has a probability proportional to 1 / (k + 1)^exponent
+struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, exponent: f64) -> Self {
+        let mut total = 0.0;
+        let mut cdf: Vec<f64> = (0..n)
+            .map(|k| {
+                total += 1.0 / ((k + 1) as f64).powf(exponent);
+                total
+            })
+            .collect();
+        for p in cdf.iter_mut() {
+            *p /= total;
+        }
+        Self { cdf }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        let p: f64 = rng.gen();
+        self.cdf.partition_point(|c| *c < p).min(self.cdf.len() - 1)
+    }
+}
+
+fn zipf_trace(exponent: f64) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(42);
+    let zipf = Zipf::new(KEY_SPACE, exponent);
+    (0..TRACE_LEN).map(|_| zipf.sample(&mut rng)).collect()
+}
+
+/// a zipf trace which is interrupted by scans of keys which are used only once
+fn zipf_with_scans_trace(exponent: f64) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(42);
+    let zipf = Zipf::new(KEY_SPACE, exponent);
+    let mut scan_key = KEY_SPACE;
+    let mut trace = Vec::with_capacity(TRACE_LEN);
+    while trace.len() < TRACE_LEN {
+        for _ in 0..5_000 {
+            trace.push(zipf.sample(&mut rng));
+        }
+        for _ in 0..2_000 {
+            trace.push(scan_key);
+            scan_key += 1;
+        }
+    }
+    trace.truncate(TRACE_LEN);
+    trace
+}
+
+/// loops over a set of keys which is slightly larger than the cache
+fn loop_trace(loop_size: usize) -> Vec<usize> {
+    (0..TRACE_LEN).map(|x| x % loop_size).collect()
+}
+
+fn hit_ratio(trace: &[usize], max_size: usize, policy: CachePolicy) -> f64 {
+    let misses = Arc::new(AtomicUsize::new(0));
+    let producer_misses = misses.clone();
+    let mut cache = Cache::new(
+        move |key: &usize| {
+            producer_misses.fetch_add(1, Ordering::Relaxed);
+            Some(*key)
+        },
+        Duration::from_secs(3600),
+        max_size,
+    )
+    // reorder on every access so Lru is exact
+    .with_inactive_resolution(Duration::ZERO)
+    .with_policy(policy);
+    for key in trace {
+        cache.get(key);
+    }
+    1.0 - misses.load(Ordering::Relaxed) as f64 / trace.len() as f64
+}
+
+fn main() {
+    let policies = [CachePolicy::Lru, CachePolicy::Lfu, CachePolicy::WTinyLfu];
+    let traces = [
+        ("zipf 0.8", zipf_trace(0.8)),
+        ("zipf 1.0", zipf_trace(1.0)),
+        ("zipf 0.8 + scans", zipf_with_scans_trace(0.8)),
+        ("loop 1100", loop_trace(1_100)),
+    ];
+
+    println!(
+        "{:<20} {:>6} {:>8} {:>8} {:>8}",
+        "trace", "size", "Lru", "Lfu", "WTinyLfu"
+    );
+    for (name, trace) in &traces {
+        for max_size in [100, 1_000] {
+            let ratios: Vec<String> = policies
+                .iter()
+                .map(|policy| format!("{:>7.2}%", hit_ratio(trace, max_size, *policy) * 100.0))
+                .collect();
+            println!("{:<20} {:>6} {}", name, max_size, ratios.join(" "));
+        }
+    }
+}
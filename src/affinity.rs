@@ -0,0 +1,122 @@
+//! utils for setting the cpu affinity and scheduling priority (niceness) of the current thread
+//!
+//! these are only implemented on linux, on other platforms the setters return an Unsupported error
+//! and the getters return None
+
+use std::io;
+
+/// pin the current thread to a set of cpu cores
+#[cfg(target_os = "linux")]
+pub fn set_current_thread_affinity(cores: &[usize]) -> io::Result<()> {
+    if cores.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no cores"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for core in cores {
+            if *core >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("core {core} out of range"),
+                ));
+            }
+            libc::CPU_SET(*core, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// pin the current thread to a set of cpu cores
+#[cfg(not(target_os = "linux"))]
+pub fn set_current_thread_affinity(_cores: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cpu affinity is only supported on linux",
+    ))
+}
+
+/// get the cpu cores the current thread may run on
+#[cfg(target_os = "linux")]
+pub fn current_thread_affinity() -> Option<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some(
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|core| libc::CPU_ISSET(*core, &set))
+                .collect(),
+        )
+    }
+}
+
+/// get the cpu cores the current thread may run on
+#[cfg(not(target_os = "linux"))]
+pub fn current_thread_affinity() -> Option<Vec<usize>> {
+    None
+}
+
+/// set the niceness of the current thread (-20 is the highest priority, 19 the lowest)
+/// please note that lowering the niceness usually requires elevated privileges
+#[cfg(target_os = "linux")]
+pub fn set_current_thread_niceness(niceness: i32) -> io::Result<()> {
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if libc::setpriority(libc::PRIO_PROCESS as _, tid, niceness) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// set the niceness of the current thread (-20 is the highest priority, 19 the lowest)
+#[cfg(not(target_os = "linux"))]
+pub fn set_current_thread_niceness(_niceness: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "thread niceness is only supported on linux",
+    ))
+}
+
+/// get the niceness of the current thread
+#[cfg(target_os = "linux")]
+pub fn current_thread_niceness() -> Option<i32> {
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        // -1 is a valid niceness so we need to check errno
+        *libc::__errno_location() = 0;
+        let niceness = libc::getpriority(libc::PRIO_PROCESS as _, tid);
+        if niceness == -1 && *libc::__errno_location() != 0 {
+            None
+        } else {
+            Some(niceness)
+        }
+    }
+}
+
+/// get the niceness of the current thread
+#[cfg(not(target_os = "linux"))]
+pub fn current_thread_niceness() -> Option<i32> {
+    None
+}
+
+/// apply the optional affinity and niceness to the current thread, failures are logged and not fatal
+pub(crate) fn apply_to_current_thread(cpu_affinity: Option<&[usize]>, niceness: Option<i32>) {
+    if let Some(cores) = cpu_affinity {
+        if let Err(e) = set_current_thread_affinity(cores) {
+            log::warn!("could not set cpu affinity {:?}: {}", cores, e);
+        }
+    }
+    if let Some(niceness) = niceness {
+        if let Err(e) = set_current_thread_niceness(niceness) {
+            log::warn!("could not set thread niceness {}: {}", niceness, e);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::cache::{Cache, CacheIFace};
+use crate::task_manager::TaskManager;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::Future;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+type Load<O> = Shared<BoxFuture<'static, Option<O>>>;
+
+/// AsyncCache is a Cache with a producer which returns a Future
+///
+/// the producer runs as an async task in a TaskManager, concurrent gets for the same missing key all await a single
+/// load (single-flight)
+/// get returns a clone of the item
+/// # Example
+/// ```rust
+/// use hirofa_utils::async_cache::AsyncCache;
+/// use hirofa_utils::task_manager::TaskManager;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// let tm = Arc::new(TaskManager::new(1));
+/// let cache = AsyncCache::new(
+///     |key: &i32| {
+///         let key = *key;
+///         async move { Some(key * 2) }
+///     },
+///     Duration::from_secs(60),
+///     10,
+///     tm,
+/// );
+/// let item = futures::executor::block_on(cache.get(&21));
+/// assert_eq!(item, Some(42));
+/// ```
+pub struct AsyncCache<K: Eq + Hash, O> {
+    inner: Arc<Mutex<AsyncCacheInner<K, O>>>,
+    #[allow(clippy::type_complexity)]
+    producer: Arc<dyn Fn(&K) -> BoxFuture<'static, Option<O>> + Send + Sync>,
+    task_manager: Arc<TaskManager>,
+}
+
+struct AsyncCacheInner<K: Eq + Hash, O> {
+    cache: Cache<K, O>,
+    // the loads which are in progress, with an id so a finished load can check it was not invalidated
+    loading: HashMap<K, (usize, Load<O>)>,
+    next_load_id: usize,
+}
+
+impl<K, O> AsyncCache<K, O>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    O: Clone + Send + Sync + 'static,
+{
+    pub fn new<P, F>(
+        producer: P,
+        max_inactive_time: Duration,
+        max_size: usize,
+        task_manager: Arc<TaskManager>,
+    ) -> Self
+    where
+        P: Fn(&K) -> F + Send + Sync + 'static,
+        F: Future<Output = Option<O>> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(Mutex::new(AsyncCacheInner {
+                cache: Cache::new(|_key: &K| None, max_inactive_time, max_size),
+                loading: HashMap::new(),
+                next_load_id: 0,
+            })),
+            producer: Arc::new(move |key: &K| producer(key).boxed()),
+            task_manager,
+        }
+    }
+
+    /// get an item, if it is not cached the producer is run in the TaskManager
+    /// if the item is already being loaded this awaits the running load instead of running the producer again
+    pub async fn get(&self, key: &K) -> Option<O> {
+        let load = {
+            let inner = &mut *self.inner.lock();
+            inner.cache.invalidate_stale();
+            if let Some(item) = inner.cache.opt(key) {
+                return Some(item.clone());
+            }
+            match inner.loading.get(key) {
+                Some((_load_id, load)) => load.clone(),
+                None => {
+                    let load_id = inner.next_load_id;
+                    inner.next_load_id = inner.next_load_id.wrapping_add(1);
+                    let load = self.start_load(key, load_id);
+                    inner.loading.insert(key.clone(), (load_id, load.clone()));
+                    load
+                }
+            }
+        };
+        load.await
+    }
+
+    fn start_load(&self, key: &K, load_id: usize) -> Load<O> {
+        let producer_fut = (self.producer)(key);
+        let inner_weak: Weak<Mutex<AsyncCacheInner<K, O>>> = Arc::downgrade(&self.inner);
+        let task_inner_weak = inner_weak.clone();
+        let key = key.clone();
+        let task_key = key.clone();
+        let task = self.task_manager.add_task_async(async move {
+            let key = task_key;
+            let res = producer_fut.await;
+            if let Some(inner) = task_inner_weak.upgrade() {
+                let inner = &mut *inner.lock();
+                // only store the result if the load was not invalidated in the meantime
+                if matches!(inner.loading.get(&key), Some((id, _load)) if *id == load_id) {
+                    inner.loading.remove(&key);
+                    if let Some(item) = res.as_ref() {
+                        inner.cache.insert(key, item.clone());
+                    }
+                }
+            }
+            res
+        });
+        async move {
+            task.await.unwrap_or_else(|e| {
+                log::error!("AsyncCache producer failed: {}", e);
+                // the task did not get to remove the load, do it here so the next get runs the producer again
+                if let Some(inner) = inner_weak.upgrade() {
+                    let inner = &mut *inner.lock();
+                    if matches!(inner.loading.get(&key), Some((id, _load)) if *id == load_id) {
+                        inner.loading.remove(&key);
+                    }
+                }
+                None
+            })
+        }
+        .boxed()
+        .shared()
+    }
+
+    /// get an item if it is cached, this never runs the producer
+    pub fn opt(&self, key: &K) -> Option<O> {
+        self.inner.lock().cache.opt(key).cloned()
+    }
+
+    pub fn insert(&self, key: K, item: O) {
+        let inner = &mut *self.inner.lock();
+        inner.loading.remove(&key);
+        inner.cache.insert(key, item);
+    }
+
+    /// remove an item, a load which is in progress for the key is not stored when it completes
+    pub fn invalidate(&self, key: &K) {
+        let inner = &mut *self.inner.lock();
+        inner.loading.remove(key);
+        inner.cache.invalidate(key);
+    }
+
+    pub fn invalidate_all(&self) {
+        let inner = &mut *self.inner.lock();
+        inner.loading.clear();
+        inner.cache.invalidate_all();
+    }
+
+    pub fn invalidate_stale(&self) {
+        self.inner.lock().cache.invalidate_stale();
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.inner.lock().cache.contains_key(key)
+    }
+
+    /// check if the producer is currently running for a key
+    pub fn is_loading(&self, key: &K) -> bool {
+        self.inner.lock().loading.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().cache.is_empty()
+    }
+}
+
+impl<K: Eq + Hash, O> Clone for AsyncCache<K, O> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            producer: self.producer.clone(),
+            task_manager: self.task_manager.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::async_cache::AsyncCache;
+    use crate::task_manager::TaskManager;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_async_cache() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let producer_loads = loads.clone();
+        let producer = move |key: &String| {
+            producer_loads.fetch_add(1, Ordering::SeqCst);
+            let key = key.clone();
+            async move {
+                // a slow producer
+                std::thread::sleep(Duration::from_millis(100));
+                Some(format!("entry: {key}"))
+            }
+        };
+        let cache = AsyncCache::new(
+            producer,
+            Duration::from_secs(60),
+            10,
+            Arc::new(TaskManager::new(2)),
+        );
+
+        let key = "a".to_string();
+        let (one, two) =
+            futures::executor::block_on(futures::future::join(cache.get(&key), cache.get(&key)));
+        assert_eq!(one.as_deref(), Some("entry: a"));
+        assert_eq!(two.as_deref(), Some("entry: a"));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(!cache.is_loading(&key));
+
+        // cached now
+        assert_eq!(
+            futures::executor::block_on(cache.get(&key)).as_deref(),
+            Some("entry: a")
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        cache.invalidate(&key);
+        assert!(cache.opt(&key).is_none());
+        let _ = futures::executor::block_on(cache.get(&key));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_async_cache_panicking_producer() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let producer_loads = loads.clone();
+        let producer = move |key: &i32| {
+            let attempt = producer_loads.fetch_add(1, Ordering::SeqCst);
+            let key = *key;
+            async move {
+                if attempt == 0 {
+                    panic!("first load fails");
+                }
+                Some(key * 2)
+            }
+        };
+        let cache = AsyncCache::new(
+            producer,
+            Duration::from_secs(60),
+            10,
+            Arc::new(TaskManager::new(1)),
+        );
+
+        assert_eq!(futures::executor::block_on(cache.get(&21)), None);
+        assert!(!cache.is_loading(&21));
+        // the failed load is not stuck, the producer runs again
+        assert_eq!(futures::executor::block_on(cache.get(&21)), Some(42));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,7 +1,37 @@
-use rand::{thread_rng, Rng};
-use std::collections::HashMap;
+use linked_hash_map::LinkedHashMap;
+use parking_lot::Mutex;
+use rand::rngs::{OsRng, StdRng};
+use rand::{thread_rng, Rng, SeedableRng};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{hash_map, HashMap};
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::iter::FromIterator;
+use std::ops::{Index, IndexMut};
+
+/// ids never use more than this amount of bits, so they can be passed to JavaScript as a number (f64) without loss
+const MAX_ID_BITS: u32 = if usize::BITS >= 64 { 53 } else { 31 };
+/// ids of maps with a small enough max_size fit in a (positive) i32
+const I32_ID_BITS: u32 = 31;
+/// the minimum amount of bits reserved for the generation of a slot
+const MIN_GENERATION_BITS: u32 = 8;
+/// the max amount of bits used for the slot, this leaves at least 21 bits for the generation in large maps
+const MAX_SLOT_BITS: u32 = 32;
 
 /// AutoIdMap is a wrapper around HashMap which automatically creates a unique id for it's entries
+///
+/// ids consist of a slot and a generation, when an entry is removed its slot gets a new generation so a stale id will not
+/// address a new entry which reuses the same slot (the generation is incremented for IdStrategy::Sequential and picked at
+/// random otherwise, so a stale id may match again only by chance)
+/// ids fit in an i32 if max_size <= 2^23, otherwise they fit in the 53 bits an f64 can represent exactly
+///
+/// inserting is O(1), by default a vacant slot is picked at random from a free list and new slots start at a random
+/// generation so ids are not predictable, see IdStrategy for other options
+///
+/// by default ids are plain usize values, a distinct key type may be declared with new_key_type! so ids of different maps
+/// can't be mixed up
 /// # Example
 /// ```no_run
 ///
@@ -14,44 +44,468 @@ use std::collections::HashMap;
 /// let s1 = map.remove(&id1);
 /// assert_eq!(s1, "hi");
 /// assert_eq!(map.len(), 1);
+/// assert!(map.get(&id1).is_none());
 /// ```
-pub struct AutoIdMap<T> {
+pub struct AutoIdMap<T, K: Key = usize> {
     max_size: usize,
-    pub map: HashMap<usize, T>,
+    slot_bits: u32,
+    generation_bits: u32,
+    // the current generation of every slot that was ever used, indexed by slot
+    generations: Vec<usize>,
+    // slots which were used before and are now vacant
+    free: Vec<usize>,
+    id_rng: IdRng,
+    // scrambles the slot part of ids, only used for IdStrategy::Csprng
+    slot_permutation: Option<SlotPermutation>,
+    eviction_policy: EvictionPolicy,
+    // the ids in insertion or usage order (oldest first), only tracked if the eviction policy is not Reject
+    order: Option<Mutex<LinkedHashMap<K, ()>>>,
+    map: HashMap<K, T>,
+}
+
+/// what an AutoIdMap does when an element is inserted while the map is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// fail the insert, this is the default
+    #[default]
+    Reject,
+    /// remove the element which was inserted first
+    Oldest,
+    /// remove the element which was least recently inserted or accessed with get, get_mut or an index
+    LeastRecentlyUsed,
+}
+
+/// the error returned when an element could not be inserted because the AutoIdMap is full, the element can be
+/// retrieved with into_inner
+pub struct InsertError<T> {
+    elem: T,
+}
+
+impl<T> InsertError<T> {
+    pub fn new(elem: T) -> Self {
+        Self { elem }
+    }
+
+    /// get the element which could not be inserted
+    pub fn into_inner(self) -> T {
+        self.elem
+    }
+}
+
+impl<T> Debug for InsertError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InsertError(AutoIdMap is full)")
+    }
+}
+
+impl<T> Display for InsertError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AutoIdMap is full")
+    }
+}
+
+impl<T> StdError for InsertError<T> {}
+
+/// the strategy an AutoIdMap uses to pick new ids
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// new slots are handed out in order starting at generation 0 and the most recently freed slot is reused first,
+    /// this makes for readable ids while debugging
+    Sequential,
+    /// ids are picked using thread_rng, this is the default
+    Random,
+    /// ids are picked using a rng seeded with the given seed so the same operations result in the same ids, useful for tests
+    Seeded(u64),
+    /// ids are picked using the cryptographically secure rng of the OS, for ids which are exposed to untrusted clients
+    /// the generation is random and the slot is scrambled with a permutation using a secret random key, so all bits of
+    /// the id are unpredictable, please note that this strategy should be set before inserting elements
+    Csprng,
+}
+
+#[derive(Clone)]
+enum IdRng {
+    Sequential,
+    Random,
+    Seeded(Box<StdRng>),
+    Csprng,
+}
+
+impl IdRng {
+    fn new(strategy: IdStrategy) -> Self {
+        match strategy {
+            IdStrategy::Sequential => IdRng::Sequential,
+            IdStrategy::Random => IdRng::Random,
+            IdStrategy::Seeded(seed) => IdRng::Seeded(Box::new(StdRng::seed_from_u64(seed))),
+            IdStrategy::Csprng => IdRng::Csprng,
+        }
+    }
+
+    /// get a random number below n, or None if ids should be sequential
+    fn below(&mut self, n: usize) -> Option<usize> {
+        match self {
+            IdRng::Sequential => None,
+            IdRng::Random => Some(thread_rng().gen_range(0..n)),
+            IdRng::Seeded(rng) => Some(rng.gen_range(0..n)),
+            IdRng::Csprng => Some(OsRng.gen_range(0..n)),
+        }
+    }
+}
+
+const SLOT_PERMUTATION_ROUNDS: usize = 4;
+
+/// a keyed permutation of the slot numbers, used by IdStrategy::Csprng so the slots of new ids are not sequential
+/// this is a Feistel network over the slot bits, values outside of the slot range are walked through the permutation
+/// again until they are in range (cycle walking)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct SlotPermutation {
+    keys: [u64; SLOT_PERMUTATION_ROUNDS],
+}
+
+impl SlotPermutation {
+    fn new() -> Self {
+        Self { keys: OsRng.gen() }
+    }
+
+    fn round(&self, round: usize, half: u64) -> u64 {
+        // the splitmix64 finalizer
+        let mut z = half ^ self.keys[round];
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn feistel(&self, x: usize, half_bits: u32, inverse: bool) -> usize {
+        let mask = (1u64 << half_bits) - 1;
+        let (mut l, mut r) = ((x as u64 >> half_bits) & mask, x as u64 & mask);
+        if inverse {
+            for round in (0..SLOT_PERMUTATION_ROUNDS).rev() {
+                let t = r ^ (self.round(round, l) & mask);
+                r = l;
+                l = t;
+            }
+        } else {
+            for round in 0..SLOT_PERMUTATION_ROUNDS {
+                let t = l ^ (self.round(round, r) & mask);
+                l = r;
+                r = t;
+            }
+        }
+        ((l << half_bits) | r) as usize
+    }
+
+    /// permute a slot below 2^slot_bits, or undo the permutation if inverse is true
+    fn apply(&self, slot: usize, slot_bits: u32, inverse: bool) -> usize {
+        if slot_bits == 0 {
+            return slot;
+        }
+        let half_bits = slot_bits.div_ceil(2);
+        let mut x = slot;
+        loop {
+            x = self.feistel(x, half_bits, inverse);
+            if x < 1 << slot_bits {
+                return x;
+            }
+        }
+    }
+}
+
+/// the key type of an AutoIdMap, the id is the raw slot and generation value of the key
+pub trait Key: Copy + Eq + Hash + Debug {
+    fn from_id(id: usize) -> Self;
+    fn id(&self) -> usize;
+}
+
+impl Key for usize {
+    fn from_id(id: usize) -> Self {
+        id
+    }
+
+    fn id(&self) -> usize {
+        *self
+    }
+}
+
+/// declare one or more key types for use with AutoIdMap
+/// # Example
+/// ```rust
+/// use hirofa_utils::auto_id_map::{AutoIdMap, Key};
+/// use hirofa_utils::new_key_type;
+///
+/// new_key_type! {
+///     pub struct TimeoutId;
+///     pub struct IntervalId;
+/// }
+///
+/// let mut timeouts: AutoIdMap<&str, TimeoutId> = AutoIdMap::new_keyed();
+/// let mut intervals: AutoIdMap<&str, IntervalId> = AutoIdMap::new_keyed();
+/// let timeout_id = timeouts.insert("timeout");
+/// let _interval_id = intervals.insert("interval");
+/// // intervals.get(&timeout_id); would not compile
+/// assert_eq!(timeouts.get(&TimeoutId::from_id(timeout_id.id())), Some(&"timeout"));
+/// ```
+#[macro_export]
+macro_rules! new_key_type {
+    ( $(#[$outer:meta])* $vis:vis struct $name:ident; $($rest:tt)* ) => {
+        $(#[$outer])*
+        #[derive(Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+        #[repr(transparent)]
+        $vis struct $name(usize);
+
+        impl $crate::auto_id_map::Key for $name {
+            fn from_id(id: usize) -> Self {
+                $name(id)
+            }
+
+            fn id(&self) -> usize {
+                self.0
+            }
+        }
+
+        $crate::new_key_type!($($rest)*);
+    };
+    () => {};
+}
+
+fn slot_bits_for_max_size(max_size: usize) -> u32 {
+    (usize::BITS - max_size.saturating_sub(1).leading_zeros()).min(MAX_SLOT_BITS)
+}
+
+/// the amount of bits ids of a map with this max_size may use
+pub(crate) fn id_bits_for_max_size(max_size: usize) -> u32 {
+    if slot_bits_for_max_size(max_size) + MIN_GENERATION_BITS <= I32_ID_BITS {
+        I32_ID_BITS
+    } else {
+        MAX_ID_BITS
+    }
 }
 
 impl<T> AutoIdMap<T> {
     /// create a new instance of the AutoIdMap
     pub fn new() -> AutoIdMap<T> {
-        Self::new_with_max_size(usize::MAX)
+        Self::new_keyed()
     }
 
+    /// create a new instance of the AutoIdMap with a maximum number of entries
     pub fn new_with_max_size(max_size: usize) -> AutoIdMap<T> {
+        Self::new_keyed_with_max_size(max_size)
+    }
+}
+
+impl<T, K: Key> AutoIdMap<T, K> {
+    /// create a new instance of the AutoIdMap with a custom key type
+    pub fn new_keyed() -> AutoIdMap<T, K> {
+        Self::new_keyed_with_max_size(usize::MAX)
+    }
+
+    /// create a new instance of the AutoIdMap with a custom key type and a maximum number of entries
+    pub fn new_keyed_with_max_size(max_size: usize) -> AutoIdMap<T, K> {
+        Self::new_keyed_with_id_bits(max_size, id_bits_for_max_size(max_size))
+    }
+
+    /// create a new instance where ids are limited to id_bits bits
+    pub(crate) fn new_keyed_with_id_bits(max_size: usize, id_bits: u32) -> AutoIdMap<T, K> {
+        let slot_bits = slot_bits_for_max_size(max_size).min(id_bits - MIN_GENERATION_BITS);
         AutoIdMap {
-            max_size,
+            max_size: max_size.min(1 << slot_bits),
+            slot_bits,
+            generation_bits: id_bits - slot_bits,
+            generations: vec![],
+            free: vec![],
+            id_rng: IdRng::Random,
+            slot_permutation: None,
+            eviction_policy: EvictionPolicy::Reject,
+            order: None,
             map: HashMap::new(),
         }
     }
 
-    pub fn foreach_value<F: Fn(&T)>(&self, f: F) {
+    /// set the strategy for picking new ids, see IdStrategy
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::auto_id_map::{AutoIdMap, IdStrategy};
+    /// let mut map = AutoIdMap::new().with_id_strategy(IdStrategy::Sequential);
+    /// assert_eq!(map.insert("a"), 0);
+    /// assert_eq!(map.insert("b"), 1);
+    /// ```
+    pub fn with_id_strategy(mut self, strategy: IdStrategy) -> Self {
+        // existing ids would no longer resolve to their slot if the permutation was added later
+        if strategy == IdStrategy::Csprng && self.generations.is_empty() {
+            self.slot_permutation = Some(SlotPermutation::new());
+        }
+        self.id_rng = IdRng::new(strategy);
+        self
+    }
+
+    /// set what to do when inserting into a full map, see EvictionPolicy
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::auto_id_map::{AutoIdMap, EvictionPolicy};
+    /// let mut map = AutoIdMap::new_with_max_size(2).with_eviction_policy(EvictionPolicy::Oldest);
+    /// let id1 = map.insert("a");
+    /// let id2 = map.insert("b");
+    /// let (_id3, evicted) = map.insert_evicting("c").unwrap();
+    /// assert_eq!(evicted, Some((id1, "a")));
+    /// assert_eq!(map.len(), 2);
+    /// ```
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction_policy = policy;
+        self.order = match policy {
+            EvictionPolicy::Reject => None,
+            _ => Some(Mutex::new(self.map.keys().map(|k| (*k, ())).collect())),
+        };
+        self
+    }
+
+    /// mark an element as used for EvictionPolicy::LeastRecentlyUsed
+    fn touch(&self, id: &K) {
+        if self.eviction_policy == EvictionPolicy::LeastRecentlyUsed {
+            if let Some(order) = self.order.as_ref() {
+                let _ = order.lock().get_refresh(id);
+            }
+        }
+    }
+
+    fn insert_at(&mut self, id: K, elem: T) {
+        self.map.insert(id, elem);
+        if let Some(order) = self.order.as_mut() {
+            order.get_mut().insert(id, ());
+        }
+    }
+
+    /// if the map is full remove an element according to the eviction policy
+    /// returns Err if the map is full and nothing could be evicted
+    fn evict_if_full(&mut self) -> Result<Option<(K, T)>, ()> {
+        if self.map.len() < self.max_size {
+            return Ok(None);
+        }
+        let oldest = self
+            .order
+            .as_mut()
+            .and_then(|order| order.get_mut().front().map(|(k, _)| *k));
+        match oldest {
+            Some(id) => Ok(self.remove_opt(&id).map(|elem| (id, elem))),
+            None => Err(()),
+        }
+    }
+
+    fn to_key(&self, slot: usize, generation: usize) -> K {
+        let slot = match self.slot_permutation.as_ref() {
+            Some(permutation) => permutation.apply(slot, self.slot_bits, false),
+            None => slot,
+        };
+        K::from_id((generation << self.slot_bits) | slot)
+    }
+
+    fn slot_of(&self, id: &K) -> usize {
+        let slot = id.id() & ((1 << self.slot_bits) - 1);
+        match self.slot_permutation.as_ref() {
+            Some(permutation) => permutation.apply(slot, self.slot_bits, true),
+            None => slot,
+        }
+    }
+
+    /// mark the slot of a removed id as free and bump its generation so the id becomes stale
+    fn free_slot(&mut self, id: &K) {
+        let slot = self.slot_of(id);
+        self.generations[slot] = self.next_generation(slot);
+        self.free.push(slot);
+        if let Some(order) = self.order.as_mut() {
+            order.get_mut().remove(id);
+        }
+    }
+
+    /// the generation a slot gets when it is freed, this is the next generation for IdStrategy::Sequential and a random
+    /// other generation otherwise, so the next id for a slot can't be derived from a stale id
+    fn next_generation(&mut self, slot: usize) -> usize {
+        let generation_count = 1 << self.generation_bits;
+        let step = self.id_rng.below(generation_count - 1).unwrap_or(0) + 1;
+        (self.generations[slot] + step) & (generation_count - 1)
+    }
+
+    pub fn foreach_value<F: FnMut(&T)>(&self, mut f: F) {
         for i in self.map.values() {
             f(i);
         }
     }
 
-    pub fn foreach_value_mut<F: Fn(&mut T)>(&mut self, f: F) {
+    pub fn foreach_value_mut<F: FnMut(&mut T)>(&mut self, mut f: F) {
         for i in self.map.values_mut() {
             f(i);
         }
     }
 
-    pub fn foreach<F: Fn(&usize, &T)>(&self, f: F) {
+    pub fn foreach<F: FnMut(&K, &T)>(&self, mut f: F) {
         for i in &self.map {
             f(i.0, i.1);
         }
     }
 
+    /// iterate over all ids and elements
+    pub fn iter(&self) -> Iter<'_, T, K> {
+        Iter {
+            inner: self.map.iter(),
+        }
+    }
+
+    /// iterate over all ids and mutable elements
+    pub fn iter_mut(&mut self) -> IterMut<'_, T, K> {
+        IterMut {
+            inner: self.map.iter_mut(),
+        }
+    }
+
+    /// iterate over all ids
+    pub fn keys(&self) -> Keys<'_, T, K> {
+        Keys {
+            inner: self.map.keys(),
+        }
+    }
+
+    /// iterate over all elements
+    pub fn values(&self) -> Values<'_, T, K> {
+        Values {
+            inner: self.map.values(),
+        }
+    }
+
+    /// iterate over all mutable elements
+    pub fn values_mut(&mut self) -> ValuesMut<'_, T, K> {
+        ValuesMut {
+            inner: self.map.values_mut(),
+        }
+    }
+
+    /// remove all elements and iterate over them, all elements are removed even if the iterator is not fully consumed
+    pub fn drain(&mut self) -> Drain<'_, T, K> {
+        let ids: Vec<K> = self.map.keys().copied().collect();
+        for id in ids {
+            self.free_slot(&id);
+        }
+        Drain {
+            inner: self.map.drain(),
+        }
+    }
+
+    /// retain only the elements for which f returns true
+    pub fn retain<F: FnMut(K, &mut T) -> bool>(&mut self, mut f: F) {
+        let mut rem_keys = vec![];
+        self.map.retain(|k, v| {
+            let keep = f(*k, v);
+            if !keep {
+                rem_keys.push(*k);
+            }
+            keep
+        });
+        for k in rem_keys {
+            self.free_slot(&k);
+        }
+    }
+
     pub fn clear(&mut self) {
+        let ids: Vec<K> = self.map.keys().copied().collect();
+        for id in ids {
+            self.free_slot(&id);
+        }
         self.map.clear();
     }
 
@@ -67,6 +521,7 @@ impl<T> AutoIdMap<T> {
         }
         for k in rem_keys {
             rems.push(self.map.remove(&k).unwrap());
+            self.free_slot(&k);
         }
         rems
     }
@@ -80,63 +535,164 @@ impl<T> AutoIdMap<T> {
         false
     }
 
-    /// insert an element and return the new id
-    pub fn insert(&mut self, elem: T) -> usize {
+    /// insert an element and return the new id, this will panic if the map is full and nothing can be evicted
+    pub fn insert(&mut self, elem: T) -> K {
         self.try_insert(elem).expect("map is full")
     }
 
     /// insert an element and return the new id
-    pub fn try_insert(&mut self, elem: T) -> Result<usize, &str> {
+    /// if the map is full an element is evicted according to the eviction policy, if nothing can be evicted the element is
+    /// returned in the InsertError
+    pub fn try_insert(&mut self, elem: T) -> Result<K, InsertError<T>> {
+        self.insert_evicting(elem).map(|(id, _evicted)| id)
+    }
+
+    /// insert an element and return the new id and the element which was evicted to make room for it (if any)
+    #[allow(clippy::type_complexity)]
+    pub fn insert_evicting(&mut self, elem: T) -> Result<(K, Option<(K, T)>), InsertError<T>> {
+        let evicted = match self.evict_if_full() {
+            Ok(evicted) => evicted,
+            Err(_) => return Err(InsertError::new(elem)),
+        };
+        match self.reserve_id() {
+            Some(id) => {
+                self.insert_at(id, elem);
+                Ok((id, evicted))
+            }
+            None => Err(InsertError::new(elem)),
+        }
+    }
+
+    /// pick a vacant slot and return the id for it, the id must be inserted or freed afterwards
+    fn reserve_id(&mut self) -> Option<K> {
         if self.map.len() >= self.max_size {
-            Err("AutoIdMap is full")
+            None
         } else {
-            let mut id = thread_rng().gen_range(0..self.max_size);
+            let slot = if self.free.is_empty() {
+                // all used slots are occupied so there must be room for a new one
+                let generation = self.id_rng.below(1 << self.generation_bits).unwrap_or(0);
+                self.generations.push(generation);
+                self.generations.len() - 1
+            } else {
+                let idx = self
+                    .id_rng
+                    .below(self.free.len())
+                    .unwrap_or(self.free.len() - 1);
+                self.free.swap_remove(idx)
+            };
 
-            while self.map.contains_key(&id) {
-                if id >= self.max_size - 1 {
-                    id = 0;
-                } else {
-                    id += 1;
-                }
-            }
+            Some(self.to_key(slot, self.generations[slot]))
+        }
+    }
+
+    /// insert an element which is created based on its new id and return the id, this will panic if the map is full
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::auto_id_map::AutoIdMap;
+    /// let mut map = AutoIdMap::new();
+    /// let id = map.insert_with(|id| format!("i am {id}"));
+    /// assert_eq!(map.get(&id).unwrap(), &format!("i am {id}"));
+    /// ```
+    pub fn insert_with<F: FnOnce(K) -> T>(&mut self, f: F) -> K {
+        self.try_insert_with(f).expect("map is full")
+    }
 
-            self.map.insert(id, elem);
-            Ok(id)
+    /// insert an element which is created based on its new id and return the id
+    pub fn try_insert_with<F: FnOnce(K) -> T>(&mut self, f: F) -> Result<K, InsertError<F>> {
+        match self.try_vacant_entry() {
+            Ok(entry) => Ok(entry.insert_with(f)),
+            Err(_) => Err(InsertError::new(f)),
+        }
+    }
+
+    /// reserve an id for an element which is inserted later, this will panic if the map is full
+    /// if the entry is dropped without inserting an element the id is released again (and will not be reused)
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::auto_id_map::AutoIdMap;
+    /// let mut map = AutoIdMap::new();
+    /// let entry = map.vacant_entry();
+    /// let id = entry.key();
+    /// entry.insert(format!("i am {id}"));
+    /// assert_eq!(map.get(&id).unwrap(), &format!("i am {id}"));
+    /// ```
+    pub fn vacant_entry(&mut self) -> VacantEntry<'_, T, K> {
+        self.try_vacant_entry().expect("map is full")
+    }
+
+    /// reserve an id for an element which is inserted later
+    /// if the map is full the element to evict is chosen now but it is only evicted when the entry is inserted
+    pub fn try_vacant_entry(&mut self) -> Result<VacantEntry<'_, T, K>, InsertError<()>> {
+        if self.map.len() >= self.max_size {
+            let victim = self
+                .order
+                .as_mut()
+                .and_then(|order| order.get_mut().front().map(|(k, _)| *k));
+            return match victim {
+                // when the map is full every slot is occupied, so the new element gets the slot of the victim with the
+                // generation it is given when the victim is removed
+                Some(victim) => {
+                    let slot = self.slot_of(&victim);
+                    let generation = self.next_generation(slot);
+                    Ok(VacantEntry {
+                        id: self.to_key(slot, generation),
+                        map: self,
+                        victim: Some(victim),
+                        inserted: false,
+                    })
+                }
+                None => Err(InsertError::new(())),
+            };
+        }
+        match self.reserve_id() {
+            Some(id) => Ok(VacantEntry {
+                map: self,
+                id,
+                victim: None,
+                inserted: false,
+            }),
+            None => Err(InsertError::new(())),
         }
     }
 
     /// replace an element, this will panic if you pass an id that is not present
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn replace(&mut self, id: &usize, elem: T) {
+    pub fn replace(&mut self, id: &K, elem: T) {
         // because we really don't want you to abuse this to insert your own id's :)
         if !self.contains_key(id) {
-            panic!("no entry to replace for {}", id);
+            panic!("no entry to replace for {:?}", id);
         }
         self.map.insert(*id, elem);
     }
 
     /// get an element based on it's id
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn get(&self, id: &usize) -> Option<&T> {
+    pub fn get(&self, id: &K) -> Option<&T> {
+        self.touch(id);
         self.map.get(id)
     }
 
     /// get an element based on it's id
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn get_mut(&mut self, id: &usize) -> Option<&mut T> {
+    pub fn get_mut(&mut self, id: &K) -> Option<&mut T> {
+        self.touch(id);
         self.map.get_mut(id)
     }
 
     /// remove an element based on its id
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn remove(&mut self, id: &usize) -> T {
-        self.map.remove(id).expect("no such elem")
+    pub fn remove(&mut self, id: &K) -> T {
+        self.remove_opt(id).expect("no such elem")
     }
 
     /// remove an element based on its id
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn remove_opt(&mut self, id: &usize) -> Option<T> {
-        self.map.remove(id)
+    pub fn remove_opt(&mut self, id: &K) -> Option<T> {
+        let res = self.map.remove(id);
+        if res.is_some() {
+            self.free_slot(id);
+        }
+        res
     }
 
     /// get the size of the map
@@ -153,48 +709,631 @@ impl<T> AutoIdMap<T> {
 
     /// check if a map contains a certain id
     #[allow(clippy::trivially_copy_pass_by_ref)]
-    pub fn contains_key(&self, id: &usize) -> bool {
+    pub fn contains_key(&self, id: &K) -> bool {
         self.map.contains_key(id)
     }
 }
 
-impl<T> Default for AutoIdMap<T> {
+/// a reserved id in an AutoIdMap, see AutoIdMap::vacant_entry
+pub struct VacantEntry<'a, T, K: Key = usize> {
+    map: &'a mut AutoIdMap<T, K>,
+    id: K,
+    // the element which is evicted when this entry is inserted into a full map
+    victim: Option<K>,
+    inserted: bool,
+}
+
+impl<'a, T, K: Key> VacantEntry<'a, T, K> {
+    /// the id the element will be inserted with
+    pub fn key(&self) -> K {
+        self.id
+    }
+
+    /// insert the element and return its id
+    pub fn insert(self, elem: T) -> K {
+        self.insert_with(|_id| elem)
+    }
+
+    /// insert an element which is created based on its id and return the id
+    pub fn insert_with<F: FnOnce(K) -> T>(mut self, f: F) -> K {
+        let id = self.id;
+        let elem = f(id);
+        if let Some(victim) = self.victim.take() {
+            if self.map.remove_opt(&victim).is_some() {
+                // take back the slot the victim just freed, it is the slot of the reserved id
+                if let Some(slot) = self.map.free.pop() {
+                    self.map.generations[slot] = id.id() >> self.map.slot_bits;
+                }
+            }
+        }
+        self.map.insert_at(id, elem);
+        self.inserted = true;
+        id
+    }
+}
+
+impl<'a, T, K: Key> Drop for VacantEntry<'a, T, K> {
+    fn drop(&mut self) {
+        // an entry for a full map did not reserve a slot, its victim was not evicted
+        if !self.inserted && self.victim.is_none() {
+            // release the slot, the generation is bumped because the id may have been handed out
+            self.map.free_slot(&self.id);
+        }
+    }
+}
+
+impl<T, K: Key> Default for AutoIdMap<T, K> {
     fn default() -> Self {
-        AutoIdMap::new()
+        AutoIdMap::new_keyed()
+    }
+}
+
+impl<T, K: Key> Index<K> for AutoIdMap<T, K> {
+    type Output = T;
+
+    fn index(&self, id: K) -> &T {
+        self.get(&id).expect("no such elem")
+    }
+}
+
+impl<T, K: Key> IndexMut<K> for AutoIdMap<T, K> {
+    fn index_mut(&mut self, id: K) -> &mut T {
+        self.get_mut(&id).expect("no such elem")
+    }
+}
+
+/// insert all elements, this will panic if the map is full
+impl<T, K: Key> Extend<T> for AutoIdMap<T, K> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for elem in iter {
+            self.insert(elem);
+        }
+    }
+}
+
+impl<T, K: Key> FromIterator<T> for AutoIdMap<T, K> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut map = AutoIdMap::new_keyed();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<T: Debug, K: Key> Debug for AutoIdMap<T, K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.map.iter()).finish()
+    }
+}
+
+impl<T: Clone, K: Key> Clone for AutoIdMap<T, K> {
+    fn clone(&self) -> Self {
+        AutoIdMap {
+            max_size: self.max_size,
+            slot_bits: self.slot_bits,
+            generation_bits: self.generation_bits,
+            generations: self.generations.clone(),
+            free: self.free.clone(),
+            id_rng: self.id_rng.clone(),
+            slot_permutation: self.slot_permutation,
+            eviction_policy: self.eviction_policy,
+            order: self
+                .order
+                .as_ref()
+                .map(|order| Mutex::new(order.lock().clone())),
+            map: self.map.clone(),
+        }
+    }
+}
+
+// the serialized form of an AutoIdMap, this includes the state of the slots so ids don't collide after deserializing
+#[derive(Serialize)]
+struct AutoIdMapSer<'a, T> {
+    max_size: usize,
+    slot_bits: u32,
+    generation_bits: u32,
+    generations: &'a [usize],
+    free: &'a [usize],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slot_permutation: Option<SlotPermutation>,
+    entries: Vec<(usize, &'a T)>,
+}
+
+#[derive(Deserialize)]
+struct AutoIdMapDe<T> {
+    max_size: usize,
+    slot_bits: u32,
+    generation_bits: u32,
+    generations: Vec<usize>,
+    free: Vec<usize>,
+    #[serde(default)]
+    slot_permutation: Option<SlotPermutation>,
+    entries: Vec<(usize, T)>,
+}
+
+/// serialize the entries with their ids and the state of the id allocator
+/// the IdStrategy and EvictionPolicy are not serialized, a deserialized map uses IdStrategy::Random and EvictionPolicy::Reject
+/// (or IdStrategy::Csprng if the map used that, because its ids can only be resolved with the key of its slot permutation)
+impl<T: Serialize, K: Key> Serialize for AutoIdMap<T, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AutoIdMapSer {
+            max_size: self.max_size,
+            slot_bits: self.slot_bits,
+            generation_bits: self.generation_bits,
+            generations: &self.generations,
+            free: &self.free,
+            slot_permutation: self.slot_permutation,
+            entries: self.map.iter().map(|(k, v)| (k.id(), v)).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, K: Key> Deserialize<'de> for AutoIdMap<T, K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = AutoIdMapDe::<T>::deserialize(deserializer)?;
+        if data.slot_bits + data.generation_bits > MAX_ID_BITS
+            || data.slot_bits > MAX_SLOT_BITS
+            || data.generation_bits < MIN_GENERATION_BITS
+            || data.max_size > 1 << data.slot_bits
+            || data.entries.len() + data.free.len() != data.generations.len()
+            || data.entries.len() > data.max_size
+            || data
+                .generations
+                .iter()
+                .any(|generation| *generation >= 1 << data.generation_bits)
+        {
+            return Err(D::Error::custom("invalid AutoIdMap state"));
+        }
+        let mut map = AutoIdMap {
+            max_size: data.max_size,
+            slot_bits: data.slot_bits,
+            generation_bits: data.generation_bits,
+            generations: data.generations,
+            free: data.free,
+            id_rng: match data.slot_permutation {
+                Some(_) => IdRng::Csprng,
+                None => IdRng::Random,
+            },
+            slot_permutation: data.slot_permutation,
+            eviction_policy: EvictionPolicy::Reject,
+            order: None,
+            map: HashMap::with_capacity(data.entries.len()),
+        };
+        // every slot must be either free or occupied by exactly one entry
+        let mut used = vec![false; map.generations.len()];
+        for slot in &map.free {
+            match used.get_mut(*slot) {
+                Some(used) if !*used => *used = true,
+                _ => return Err(D::Error::custom("invalid AutoIdMap free slot")),
+            }
+        }
+        for (id, elem) in data.entries {
+            let key = K::from_id(id);
+            let slot = map.slot_of(&key);
+            if map.generations.get(slot) != Some(&(id >> map.slot_bits))
+                || std::mem::replace(&mut used[slot], true)
+                || map.map.insert(key, elem).is_some()
+            {
+                return Err(D::Error::custom(format!("invalid AutoIdMap id {id}")));
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl<T, K: Key> IntoIterator for AutoIdMap<T, K> {
+    type Item = (K, T);
+    type IntoIter = IntoIter<T, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            inner: self.map.into_iter(),
+        }
+    }
+}
+
+impl<'a, T, K: Key> IntoIterator for &'a AutoIdMap<T, K> {
+    type Item = (K, &'a T);
+    type IntoIter = Iter<'a, T, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T, K: Key> IntoIterator for &'a mut AutoIdMap<T, K> {
+    type Item = (K, &'a mut T);
+    type IntoIter = IterMut<'a, T, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+// implement Iterator and ExactSizeIterator for a wrapper around a hash_map iterator
+macro_rules! impl_iterator {
+    ($name:ident, $item:ty, |$i:ident| $map:expr) => {
+        impl<'a, T, K: Key> Iterator for $name<'a, T, K> {
+            type Item = $item;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                self.inner.next().map(|$i| $map)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.inner.size_hint()
+            }
+        }
+
+        impl<'a, T, K: Key> ExactSizeIterator for $name<'a, T, K> {}
+    };
+}
+
+/// iterator over the ids and elements of an AutoIdMap
+pub struct Iter<'a, T, K: Key = usize> {
+    inner: hash_map::Iter<'a, K, T>,
+}
+impl_iterator!(Iter, (K, &'a T), |i| (*i.0, i.1));
+
+/// iterator over the ids and mutable elements of an AutoIdMap
+pub struct IterMut<'a, T, K: Key = usize> {
+    inner: hash_map::IterMut<'a, K, T>,
+}
+impl_iterator!(IterMut, (K, &'a mut T), |i| (*i.0, i.1));
+
+/// iterator over the ids of an AutoIdMap
+pub struct Keys<'a, T, K: Key = usize> {
+    inner: hash_map::Keys<'a, K, T>,
+}
+impl_iterator!(Keys, K, |k| *k);
+
+/// iterator over the elements of an AutoIdMap
+pub struct Values<'a, T, K: Key = usize> {
+    inner: hash_map::Values<'a, K, T>,
+}
+impl_iterator!(Values, &'a T, |v| v);
+
+/// iterator over the mutable elements of an AutoIdMap
+pub struct ValuesMut<'a, T, K: Key = usize> {
+    inner: hash_map::ValuesMut<'a, K, T>,
+}
+impl_iterator!(ValuesMut, &'a mut T, |v| v);
+
+/// draining iterator over the ids and elements of an AutoIdMap
+pub struct Drain<'a, T, K: Key = usize> {
+    inner: hash_map::Drain<'a, K, T>,
+}
+impl_iterator!(Drain, (K, T), |i| i);
+
+/// owning iterator over the ids and elements of an AutoIdMap
+pub struct IntoIter<T, K: Key = usize> {
+    inner: hash_map::IntoIter<K, T>,
+}
+
+impl<T, K: Key> Iterator for IntoIter<T, K> {
+    type Item = (K, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
     }
 }
 
+impl<T, K: Key> ExactSizeIterator for IntoIter<T, K> {}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::auto_id_map::AutoIdMap;
+    use crate::auto_id_map::{AutoIdMap, EvictionPolicy, IdStrategy, Key, SlotPermutation};
 
     #[test]
     fn test_aim() {
         let mut map = AutoIdMap::new_with_max_size(8);
+        let mut ids = vec![];
         for _x in 0..8 {
-            map.insert("foo");
+            ids.push(map.insert("foo"));
         }
         assert_eq!(map.len(), 8);
-        map.remove(&5);
+        map.remove(&ids[5]);
         let free_id = map.insert("fail?");
 
-        assert_eq!(free_id, 5);
+        // the slot is reused but the stale id does not address the new entry
+        assert_ne!(free_id, ids[5]);
+        assert!(map.get(&ids[5]).is_none());
+        assert!(map.remove_opt(&ids[5]).is_none());
+        assert_eq!(map.get(&free_id), Some(&"fail?"));
     }
 
     #[test]
     fn test_aim_ms() {
         let mut map = AutoIdMap::new_with_max_size(8);
+        let mut ids = vec![];
         for _x in 0..8 {
-            map.insert("foo");
+            ids.push(map.insert("foo"));
         }
         assert_eq!(map.len(), 8);
-        map.remove(&5);
+        map.remove(&ids[5]);
         let free_id = map.insert("fail?");
 
-        assert_eq!(free_id, 5);
+        assert!(map.contains_key(&free_id));
 
         let res = map.try_insert("foobar");
         // should be full
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_aim_id_size() {
+        let mut map = AutoIdMap::new_with_max_size(1 << 20);
+        for _x in 0..1000 {
+            let id = map.insert("foo");
+            map.remove(&id);
+            assert!(id <= i32::MAX as usize);
+        }
+
+        let mut map = AutoIdMap::new();
+        for _x in 0..1000 {
+            let id = map.insert("foo");
+            map.remove(&id);
+            assert!(id < (1 << 53));
+        }
+    }
+
+    #[test]
+    fn test_aim_keyed() {
+        crate::new_key_type! {
+            struct FooId;
+        }
+
+        let mut map: AutoIdMap<&str, FooId> = AutoIdMap::new_keyed_with_max_size(8);
+        let id = map.insert("foo");
+        assert_eq!(map.get(&id), Some(&"foo"));
+        assert_eq!(map.get(&FooId::from_id(id.id())), Some(&"foo"));
+        assert_eq!(map.remove(&id), "foo");
+        assert!(map.get(&id).is_none());
+    }
+
+    #[test]
+    fn test_aim_iter() {
+        let mut map: AutoIdMap<i32> = (0..10).collect();
+        assert_eq!(map.len(), 10);
+        assert_eq!(map.values().sum::<i32>(), 45);
+        for (id, v) in map.iter() {
+            assert_eq!(map[id], *v);
+        }
+
+        for v in map.values_mut() {
+            *v *= 2;
+        }
+        let id = map.keys().next().unwrap();
+        map[id] += 1;
+        assert_eq!(map.values().sum::<i32>(), 91);
+
+        let mut count = 0;
+        map.foreach_value(|_v| count += 1);
+        assert_eq!(count, 10);
+
+        map.retain(|_id, v| *v % 2 == 0);
+        assert_eq!(map.len(), 9);
+        assert!(map.get(&id).is_none());
+
+        let clone = map.clone();
+        assert_eq!(format!("{:?}", clone), format!("{:?}", map));
+
+        map.extend(vec![100, 200]);
+        assert_eq!(map.len(), 11);
+
+        let drained: Vec<(usize, i32)> = map.drain().collect();
+        assert_eq!(drained.len(), 11);
+        assert!(map.is_empty());
+        assert_eq!(clone.into_iter().count(), 9);
+    }
+
+    #[test]
+    fn test_aim_strategies() {
+        let mut map = AutoIdMap::new_with_max_size(8).with_id_strategy(IdStrategy::Sequential);
+        let ids: Vec<usize> = (0..4).map(|x| map.insert(x)).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+
+        fn seeded_ids(strategy: IdStrategy) -> Vec<usize> {
+            let mut map = AutoIdMap::new().with_id_strategy(strategy);
+            let mut ids = vec![];
+            for x in 0..100 {
+                let id = map.insert(x);
+                if x % 3 == 0 {
+                    map.remove(&id);
+                }
+                ids.push(id);
+            }
+            ids
+        }
+        assert_eq!(
+            seeded_ids(IdStrategy::Seeded(42)),
+            seeded_ids(IdStrategy::Seeded(42))
+        );
+        assert_ne!(
+            seeded_ids(IdStrategy::Seeded(42)),
+            seeded_ids(IdStrategy::Seeded(43))
+        );
+
+        let mut map = AutoIdMap::new_with_max_size(8).with_id_strategy(IdStrategy::Csprng);
+        for x in 0..8 {
+            map.insert(x);
+        }
+        assert!(map.try_insert(8).is_err());
+
+        // the slots of Csprng ids are scrambled as well, but every id still resolves to its own element
+        let mut map = AutoIdMap::new().with_id_strategy(IdStrategy::Csprng);
+        let ids: Vec<usize> = (0..100).map(|x| map.insert(x)).collect();
+        let slot_mask = (1 << map.slot_bits) - 1;
+        let slots: Vec<usize> = ids.iter().map(|id| id & slot_mask).collect();
+        assert!(slots.iter().any(|slot| *slot >= 100));
+        for (x, id) in ids.iter().enumerate() {
+            assert_eq!(map[*id], x);
+        }
+        let json = serde_json::to_string(&map).expect("serialize failed");
+        let restored: AutoIdMap<usize> = serde_json::from_str(&json).expect("deserialize failed");
+        for (x, id) in ids.iter().enumerate() {
+            assert_eq!(restored[*id], x);
+        }
+        // a reused slot gets a random new generation, so the new id can't be derived from the stale one
+        let mut map = AutoIdMap::new_with_max_size(1).with_id_strategy(IdStrategy::Csprng);
+        let generation_mask = (1 << map.generation_bits) - 1;
+        let mut steps = vec![];
+        for x in 0..20 {
+            let stale = map.insert(x);
+            map.remove(&stale);
+            let id = map.insert(x);
+            assert_ne!(id, stale);
+            steps.push(
+                ((id >> map.slot_bits).wrapping_sub(stale >> map.slot_bits)) & generation_mask,
+            );
+            map.remove(&id);
+        }
+        steps.sort();
+        steps.dedup();
+        assert!(steps.len() > 1);
+
+        let permutation = SlotPermutation::new();
+        for slot_bits in 0..10 {
+            let mut permuted: Vec<usize> = (0..1 << slot_bits)
+                .map(|slot| {
+                    let permuted = permutation.apply(slot, slot_bits, false);
+                    assert_eq!(permutation.apply(permuted, slot_bits, true), slot);
+                    permuted
+                })
+                .collect();
+            permuted.sort();
+            assert_eq!(permuted, (0..1 << slot_bits).collect::<Vec<usize>>());
+        }
+        let mut map = AutoIdMap::new_with_max_size(5).with_id_strategy(IdStrategy::Csprng);
+        let mut ids: Vec<usize> = (0..5).map(|x| map.insert(x)).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn test_aim_serde() {
+        let mut map = AutoIdMap::new_with_max_size(8);
+        let ids: Vec<usize> = (0..8).map(|x| map.insert(format!("v{x}"))).collect();
+        map.remove(&ids[3]);
+        map.remove(&ids[5]);
+
+        let json = serde_json::to_string(&map).expect("serialize failed");
+        let mut restored: AutoIdMap<String> =
+            serde_json::from_str(&json).expect("deserialize failed");
+
+        assert_eq!(restored.len(), 6);
+        for id in &ids {
+            assert_eq!(restored.get(id), map.get(id));
+        }
+        // the allocator state is restored, so new ids don't collide and stale ids stay stale
+        let new_ids: Vec<usize> = (0..2).map(|x| restored.insert(format!("n{x}"))).collect();
+        assert!(restored.try_insert("full".to_string()).is_err());
+        for id in new_ids {
+            assert!(!ids.contains(&id));
+        }
+
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":3,"generation_bits":28,"generations":[0],"free":[],"entries":[[9,"x"]]}"#
+        )
+        .is_err());
+        // a free slot which is also occupied
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":3,"generation_bits":28,"generations":[0,0],"free":[0],"entries":[[0,"live"]]}"#
+        )
+        .is_err());
+        // a duplicate free slot
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":3,"generation_bits":28,"generations":[0,0],"free":[0,0],"entries":[]}"#
+        )
+        .is_err());
+        // a max_size which does not fit in the slot bits
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":100,"slot_bits":1,"generation_bits":28,"generations":[],"free":[],"entries":[]}"#
+        )
+        .is_err());
+        // too few generation bits to make stale ids stale
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":3,"generation_bits":0,"generations":[],"free":[],"entries":[]}"#
+        )
+        .is_err());
+        // more slot bits than a map can be created with
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":40,"generation_bits":8,"generations":[],"free":[],"entries":[]}"#
+        )
+        .is_err());
+        // a generation which does not fit in the generation bits
+        assert!(serde_json::from_str::<AutoIdMap<String>>(
+            r#"{"max_size":8,"slot_bits":3,"generation_bits":8,"generations":[256],"free":[0],"entries":[]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_aim_entry() {
+        let mut map = AutoIdMap::new_with_max_size(2);
+        let id = map.insert_with(|id| id);
+        assert_eq!(map.get(&id), Some(&id));
+
+        let entry = map.vacant_entry();
+        let dropped_id = entry.key();
+        drop(entry);
+        assert!(map.get(&dropped_id).is_none());
+        assert_eq!(map.len(), 1);
+
+        let entry = map.try_vacant_entry().expect("map is full");
+        let id2 = entry.key();
+        assert_ne!(id2, dropped_id);
+        assert_eq!(entry.insert(id2), id2);
+
+        assert!(map.try_vacant_entry().is_err());
+        assert!(map.try_insert_with(|id| id).is_err());
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_aim_eviction() {
+        let mut map = AutoIdMap::new_with_max_size(2);
+        map.insert("a");
+        map.insert("b");
+        let err = map.try_insert("c").expect_err("map should be full");
+        assert_eq!(err.to_string(), "AutoIdMap is full");
+        assert_eq!(err.into_inner(), "c");
+
+        let mut map =
+            AutoIdMap::new_with_max_size(2).with_eviction_policy(EvictionPolicy::LeastRecentlyUsed);
+        let a = map.insert("a");
+        let b = map.insert("b");
+        // touch a so b is the least recently used
+        assert_eq!(map[a], "a");
+        let (c, evicted) = map.insert_evicting("c").expect("insert failed");
+        assert_eq!(evicted, Some((b, "b")));
+        assert!(map.contains_key(&a));
+
+        map.remove(&a);
+        let d = map.insert("d");
+        let (_e, evicted) = map.insert_evicting("e").expect("insert failed");
+        assert_eq!(evicted, Some((c, "c")));
+        assert_eq!(map.get(&d), Some(&"d"));
+
+        let mut map = AutoIdMap::new_with_max_size(1).with_eviction_policy(EvictionPolicy::Oldest);
+        let a = map.insert(1);
+        let b = map.insert_with(|_id| 2);
+        assert!(map.get(&a).is_none());
+        assert_eq!(map[b], 2);
+
+        // a vacant entry only evicts when it is inserted
+        drop(map.vacant_entry());
+        assert_eq!(map.get(&b), Some(&2));
+        let entry = map.vacant_entry();
+        let c = entry.key();
+        assert_eq!(entry.insert(3), c);
+        assert!(map.get(&b).is_none());
+        assert_eq!(map[c], 3);
+        assert_eq!(map.len(), 1);
+    }
 }
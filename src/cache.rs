@@ -1,5 +1,18 @@
+use crate::eventloop::EventLoop;
+use crate::task_manager::TaskManager;
 use linked_hash_map::LinkedHashMap;
-use std::ops::{Div, Sub};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Div;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 
 pub trait CacheIFace<K: std::cmp::Eq, O> {
@@ -18,55 +31,943 @@ pub trait CacheIFace<K: std::cmp::Eq, O> {
 struct CacheEntry<O> {
     item: O,
     last_used: Instant,
+    // when the item was produced or inserted, for refresh_after
+    loaded_at: Instant,
+    expires_at: Option<Instant>,
+    // the ttl the entry was inserted with, reapplied when the entry is reloaded
+    ttl: Option<Duration>,
+    weight: usize,
+    // the number of times the entry was used, for CachePolicy::Lfu
+    frequency: usize,
 }
 
-pub struct Cache<K: std::cmp::Eq + std::hash::Hash, O> {
+/// the policy which decides which entry is evicted when a Cache is full, see Cache::with_policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    /// evict the least recently used entry, this is the default
+    #[default]
+    Lru,
+    /// evict the least frequently used entry, of entries which are used equally often the least recently used is evicted
+    /// the entry which was inserted last is never evicted and please note that finding the victim scans all entries
+    Lfu,
+    /// new entries enter a small LRU window (1% of max_size), when an entry leaves the window it only replaces the least
+    /// recently used entry of the main region if it was used more often (according to a frequency sketch)
+    /// this keeps frequently used entries in the cache when many entries are used only once (e.g. a scan)
+    WTinyLfu,
+}
+
+/// a count-min sketch with 4 bit counters which are halved periodically so old usage is forgotten
+struct FrequencySketch {
+    hasher: RandomState,
+    width: usize,
+    table: Vec<u8>,
+    additions: usize,
+    sample_size: usize,
+}
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_MAX_COUNT: u8 = 15;
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.clamp(16, 1 << 20);
+        // a wider table than the capacity means less collisions
+        let width = (capacity * 4).next_power_of_two();
+        Self {
+            hasher: RandomState::new(),
+            width,
+            table: vec![0; width * SKETCH_DEPTH],
+            additions: 0,
+            sample_size: capacity * 10,
+        }
+    }
+
+    fn indexes<K: Hash>(&self, key: &K) -> [usize; SKETCH_DEPTH] {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash as usize, (hash >> 32) as usize | 1);
+        let mut indexes = [0; SKETCH_DEPTH];
+        for (row, idx) in indexes.iter_mut().enumerate() {
+            *idx = row * self.width + (h1.wrapping_add(row.wrapping_mul(h2)) & (self.width - 1));
+        }
+        indexes
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.indexes(key)
+            .iter()
+            .map(|idx| self.table[*idx])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for idx in self.indexes(key) {
+            if self.table[idx] < SKETCH_MAX_COUNT {
+                self.table[idx] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for count in self.table.iter_mut() {
+                *count /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+}
+
+/// the state of CachePolicy::WTinyLfu, the keys of the window and main region in LRU order
+struct WTinyLfu<K: Eq + Hash> {
+    sketch: FrequencySketch,
+    window: LinkedHashMap<K, ()>,
+    main: LinkedHashMap<K, ()>,
+    window_size: usize,
+    // the key which moved from the window to the main region during the last insert, when evicting it competes with
+    // the least recently used key of the main region
+    candidate: Option<K>,
+}
+
+impl<K: Eq + Hash + Clone> WTinyLfu<K> {
+    fn new(max_size: usize) -> Self {
+        Self {
+            sketch: FrequencySketch::new(max_size),
+            window: LinkedHashMap::new(),
+            main: LinkedHashMap::new(),
+            window_size: (max_size / 100).max(1),
+            candidate: None,
+        }
+    }
+
+    fn on_insert(&mut self, key: &K) {
+        self.candidate = None;
+        self.window.insert(key.clone(), ());
+        if self.window.len() > self.window_size {
+            if let Some((candidate, _)) = self.window.pop_front() {
+                self.main.insert(candidate.clone(), ());
+                self.candidate = Some(candidate);
+            }
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.window.remove(key).is_none() {
+            self.main.remove(key);
+        }
+        if self.candidate.as_ref() == Some(key) {
+            self.candidate = None;
+        }
+    }
+
+    fn on_access(&mut self, key: &K, hit: bool) {
+        self.sketch.increment(key);
+        if hit && self.window.get_refresh(key).is_none() {
+            self.main.get_refresh(key);
+        }
+    }
+
+    fn select_victim(&mut self) -> Option<K> {
+        let victim = self.main.front().map(|(key, _)| key.clone());
+        match (self.candidate.take(), victim) {
+            (Some(candidate), Some(victim)) if candidate != victim => {
+                if self.sketch.frequency(&candidate) > self.sketch.frequency(&victim) {
+                    Some(victim)
+                } else {
+                    Some(candidate)
+                }
+            }
+            (_, Some(victim)) => Some(victim),
+            (_, None) => self.window.front().map(|(key, _)| key.clone()),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.window.clear();
+        self.main.clear();
+        self.candidate = None;
+    }
+}
+
+/// the reason an entry was removed from a Cache, see Cache::with_removal_listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// the entry outlived its ttl or was inactive for longer than max_inactive_time
+    Expired,
+    /// the entry was evicted because the cache exceeded its max_size or max_weight
+    Capacity,
+    /// the entry was removed with invalidate
+    Explicit,
+    /// the entry was replaced by an insert with the same key
+    Replaced,
+    /// the entry was removed with invalidate_all
+    Cleared,
+}
+
+/// statistics of a Cache, see Cache::with_stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// the number of get and get_mut calls which found a cached entry (or a cached None, see with_negative_ttl)
+    pub hits: u64,
+    /// the number of get and get_mut calls which did not find a cached entry
+    pub misses: u64,
+    /// the number of times the producer was called
+    pub loads: u64,
+    /// the number of times the producer returned None
+    pub load_nones: u64,
+    /// the number of times the producer returned an error
+    pub load_errors: u64,
+    /// the total time spent in the producer
+    pub load_time: Duration,
+    pub evicted_expired: u64,
+    pub evicted_capacity: u64,
+    pub removed_explicit: u64,
+    pub removed_replaced: u64,
+    pub removed_cleared: u64,
+}
+
+impl CacheStats {
+    /// the fraction of get and get_mut calls which found a cached entry, this is 1.0 if there were no calls
+    pub fn hit_ratio(&self) -> f64 {
+        let requests = self.hits + self.misses;
+        if requests == 0 {
+            1.0
+        } else {
+            self.hits as f64 / requests as f64
+        }
+    }
+
+    /// the average time spent in the producer
+    pub fn average_load_time(&self) -> Duration {
+        if self.loads == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos((self.load_time.as_nanos() / self.loads as u128) as u64)
+        }
+    }
+
+    fn record_removal(&mut self, cause: RemovalCause, count: u64) {
+        let counter = match cause {
+            RemovalCause::Expired => &mut self.evicted_expired,
+            RemovalCause::Capacity => &mut self.evicted_capacity,
+            RemovalCause::Explicit => &mut self.removed_explicit,
+            RemovalCause::Replaced => &mut self.removed_replaced,
+            RemovalCause::Cleared => &mut self.removed_cleared,
+        };
+        *counter += count;
+    }
+}
+
+/// the source of the current time for a Cache, see Cache::with_clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// the default Clock which uses Instant::now
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// a Clock which only moves when it is advanced, this is useful for testing expiry
+/// clones share the same time
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
+
+/// a snapshot of the entries of a Cache, see Cache::snapshot and Cache::restore
+/// entries are ordered from least to most recently used
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheSnapshot<K, O> {
+    pub entries: Vec<CacheSnapshotEntry<K, O>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheSnapshotEntry<K, O> {
+    pub key: K,
+    pub item: O,
+    /// the time since the entry was last used when the snapshot was made
+    pub idle: Duration,
+    /// the time the entry had left to live when the snapshot was made
+    #[serde(default)]
+    pub ttl: Option<Duration>,
+}
+
+pub struct Cache<K: std::cmp::Eq + std::hash::Hash, O, E = Infallible> {
     // on every get remove and add (oldest items come first)
     entries: LinkedHashMap<K, CacheEntry<O>>,
-    #[allow(clippy::type_complexity)]
-    producer: Box<dyn Fn(&K) -> Option<O> + Send>,
+    producer: Producer<K, O, E>,
+    negative_ttl: Option<Duration>,
+    // keys for which the producer returned None with the time they expire (None if the negative_ttl overflows the clock),
+    // ordered by expiry
+    negative_entries: LinkedHashMap<K, Option<Instant>>,
     max_inactive_time: Duration,
     inactive_resolution: Duration,
     max_size: usize,
+    ttl: Option<Duration>,
+    // the earliest expires_at of all entries, used to skip scanning for expired entries
+    next_expiry: Option<Instant>,
+    clock: Arc<dyn Clock>,
+    #[allow(clippy::type_complexity)]
+    weigher: Option<Box<dyn Fn(&K, &O) -> usize + Send>>,
+    max_weight: usize,
+    weighted_size: usize,
+    #[allow(clippy::type_complexity)]
+    removal_listener: Option<Box<dyn Fn(K, O, RemovalCause) + Send>>,
+    policy: CachePolicy,
+    w_tiny_lfu: Option<WTinyLfu<K>>,
+    stats: Option<CacheStats>,
+    refresh: Option<Refresh<K, O, E>>,
+    // the last produced item which was heavier than max_weight, get returns it once without caching it
+    uncached: Option<(K, O)>,
+}
+
+type ProducerFn<K, O, E> = dyn Fn(&K) -> Result<Option<O>, E> + Send;
+
+/// the producer of a Cache, it is shared with the reload tasks of Cache::with_refresh_after
+enum Producer<K, O, E> {
+    Owned(Box<ProducerFn<K, O, E>>),
+    Shared(Arc<Mutex<Box<ProducerFn<K, O, E>>>>),
+}
+
+impl<K, O, E> Producer<K, O, E> {
+    fn call(&self, key: &K) -> Result<Option<O>, E> {
+        match self {
+            Producer::Owned(producer) => producer(key),
+            Producer::Shared(producer) => (producer.lock())(key),
+        }
+    }
+}
+
+/// the state of Cache::with_refresh_after
+struct Refresh<K, O, E> {
+    refresh_after: Duration,
+    // the keys which are currently being reloaded with the id of their reload, insert and invalidate remove the key so
+    // the result of a reload which was started before is ignored
+    in_flight: HashMap<K, u64>,
+    next_reload_id: u64,
+    spawn_reload: Box<dyn Fn(K, u64) + Send>,
+    #[allow(clippy::type_complexity)]
+    reloaded: Receiver<(K, u64, Result<Option<O>, E>)>,
 }
 
 impl<K: std::cmp::Eq + std::hash::Hash, O> Cache<K, O> {
     pub fn new<P>(producer: P, max_inactive_time: Duration, max_size: usize) -> Self
     where
         P: Fn(&K) -> Option<O> + Send + 'static,
+    {
+        Self::new_fallible(
+            move |key: &K| Ok(producer(key)),
+            max_inactive_time,
+            max_size,
+        )
+    }
+}
+
+impl<K: std::cmp::Eq + std::hash::Hash, O, E> Cache<K, O, E> {
+    /// create a Cache with a producer which may fail, use try_get to get the error of a failed producer
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::Cache;
+    /// use std::time::Duration;
+    /// let mut cache = Cache::new_fallible(
+    ///     |key: &i32| match key {
+    ///         0 => Err("zero is not allowed"),
+    ///         1 => Ok(None),
+    ///         _ => Ok(Some(key * 2)),
+    ///     },
+    ///     Duration::from_secs(60),
+    ///     10,
+    /// );
+    /// assert_eq!(cache.try_get(&0), Err("zero is not allowed"));
+    /// assert_eq!(cache.try_get(&1), Ok(None));
+    /// assert_eq!(cache.try_get(&2), Ok(Some(&4)));
+    /// ```
+    pub fn new_fallible<P>(producer: P, max_inactive_time: Duration, max_size: usize) -> Self
+    where
+        P: Fn(&K) -> Result<Option<O>, E> + Send + 'static,
     {
         let inactive_resolution = max_inactive_time.div(10);
         Cache {
             entries: LinkedHashMap::new(),
-            producer: Box::new(producer),
+            producer: Producer::Owned(Box::new(producer)),
+            negative_ttl: None,
+            negative_entries: LinkedHashMap::new(),
             max_inactive_time,
             inactive_resolution,
             max_size,
+            ttl: None,
+            next_expiry: None,
+            clock: Arc::new(SystemClock),
+            weigher: None,
+            max_weight: usize::MAX,
+            weighted_size: 0,
+            removal_listener: None,
+            policy: CachePolicy::Lru,
+            w_tiny_lfu: None,
+            stats: None,
+            refresh: None,
+            uncached: None,
+        }
+    }
+
+    /// remember keys for which the producer returned None for negative_ttl, so get does not call the producer again
+    /// for those keys until then
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = Some(negative_ttl);
+        self
+    }
+
+    /// keep statistics about hits, misses, loads and removals, see Cache::stats
+    pub fn with_stats(mut self) -> Self {
+        self.stats = Some(CacheStats::default());
+        self
+    }
+
+    /// get a snapshot of the statistics, this returns None if the cache was not created with_stats
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::{Cache, CacheIFace};
+    /// use std::time::Duration;
+    /// let mut cache = Cache::new(|key: &i32| Some(*key), Duration::from_secs(60), 10).with_stats();
+    /// cache.get(&1);
+    /// cache.get(&1);
+    /// let stats = cache.stats().unwrap();
+    /// assert_eq!((stats.hits, stats.misses, stats.loads), (1, 1, 1));
+    /// assert_eq!(stats.hit_ratio(), 0.5);
+    /// ```
+    pub fn stats(&self) -> Option<CacheStats> {
+        self.stats.clone()
+    }
+
+    /// reset all statistics to zero
+    pub fn reset_stats(&mut self) {
+        if let Some(stats) = self.stats.as_mut() {
+            *stats = CacheStats::default();
+        }
+    }
+
+    /// set how long an entry has to be unused before using it moves it to the back of the LRU order again
+    /// this defaults to max_inactive_time / 10 so entries are not reordered on every get, a lower value makes
+    /// CachePolicy::Lru more accurate
+    pub fn with_inactive_resolution(mut self, inactive_resolution: Duration) -> Self {
+        self.inactive_resolution = inactive_resolution;
+        self
+    }
+
+    /// limit the cache by the total weight of its entries instead of only by the number of entries
+    /// the weigher is called once when an entry is inserted, when the total weight exceeds max_weight the least
+    /// recently used entries are evicted until the total weight is within max_weight again
+    /// an item which is heavier than max_weight on its own is never cached, get returns it but a next get calls the
+    /// producer again
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::{Cache, CacheIFace};
+    /// use std::time::Duration;
+    /// let mut cache = Cache::new(|_key: &i32| None, Duration::from_secs(60), 100)
+    ///     .with_weigher(10, |_key, item: &String| item.len());
+    /// cache.insert(1, "12345".to_string());
+    /// cache.insert(2, "12345".to_string());
+    /// assert_eq!(cache.weighted_size(), 10);
+    /// cache.insert(3, "123".to_string());
+    /// assert!(!cache.contains_key(&1));
+    /// assert_eq!(cache.weighted_size(), 8);
+    /// ```
+    pub fn with_weigher<W>(mut self, max_weight: usize, weigher: W) -> Self
+    where
+        W: Fn(&K, &O) -> usize + Send + 'static,
+    {
+        self.weigher = Some(Box::new(weigher));
+        self.max_weight = max_weight;
+        self
+    }
+
+    /// set a time to live for all entries, entries expire this long after they were inserted even if they are used
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// set the Clock used to determine if entries are expired
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::{Cache, CacheIFace, ManualClock};
+    /// use std::time::Duration;
+    /// let clock = ManualClock::new();
+    /// let mut cache = Cache::new(|key: &i32| Some(*key), Duration::from_secs(60), 10)
+    ///     .with_clock(clock.clone());
+    /// cache.insert_with_ttl(1, 1, Duration::from_secs(5));
+    /// clock.advance(Duration::from_secs(6));
+    /// assert!(!cache.contains_key(&1));
+    /// ```
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn is_expired(entry: &CacheEntry<O>, now: Instant) -> bool {
+        matches!(entry.expires_at, Some(expires_at) if expires_at.le(&now))
+    }
+
+    fn track_expiry(&mut self, expires_at: Option<Instant>) {
+        if let Some(expires_at) = expires_at {
+            if self
+                .next_expiry
+                .map(|ne| expires_at.lt(&ne))
+                .unwrap_or(true)
+            {
+                self.next_expiry = Some(expires_at);
+            }
+        }
+    }
+
+    /// set a listener which is called with the key and item of every entry which is removed from the cache
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::{Cache, CacheIFace, RemovalCause};
+    /// use std::time::Duration;
+    /// let mut cache = Cache::new(|_key: &i32| None, Duration::from_secs(60), 1)
+    ///     .with_removal_listener(|key, item: String, cause| {
+    ///         assert_eq!((key, item.as_str(), cause), (1, "one", RemovalCause::Capacity));
+    ///     });
+    /// cache.insert(1, "one".to_string());
+    /// cache.insert(2, "two".to_string());
+    /// ```
+    pub fn with_removal_listener<L>(mut self, listener: L) -> Self
+    where
+        L: Fn(K, O, RemovalCause) + Send + 'static,
+    {
+        self.removal_listener = Some(Box::new(listener));
+        self
+    }
+
+    fn notify_removal(&mut self, key: K, item: O, cause: RemovalCause) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record_removal(cause, 1);
+        }
+        if let Some(listener) = self.removal_listener.as_ref() {
+            listener(key, item, cause);
         }
     }
+
     pub fn len(&self) -> usize {
         self.entries.len()
     }
+    /// the total weight of all entries, this equals len() if no weigher was set
+    pub fn weighted_size(&self) -> usize {
+        self.weighted_size
+    }
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
 }
 
-impl<K: std::cmp::Eq + std::hash::Hash + Clone, O> CacheIFace<K, O> for Cache<K, O> {
-    fn invalidate_all(&mut self) {
-        self.entries.clear();
+impl<K: std::cmp::Eq + std::hash::Hash + Clone, O, E> Cache<K, O, E> {
+    /// create a snapshot of all entries, e.g. to persist them with serde
+    pub fn snapshot(&self) -> CacheSnapshot<K, O>
+    where
+        O: Clone,
+    {
+        let now = self.clock.now();
+        CacheSnapshot {
+            entries: self
+                .entries
+                .iter()
+                .map(|(key, entry)| CacheSnapshotEntry {
+                    key: key.clone(),
+                    item: entry.item.clone(),
+                    idle: now.duration_since(entry.last_used),
+                    ttl: entry
+                        .expires_at
+                        .map(|expires_at| expires_at.saturating_duration_since(now)),
+                })
+                .collect(),
+        }
     }
 
-    fn invalidate_stale(&mut self) {
-        let now = Instant::now();
-        let max_age = now.sub(self.max_inactive_time);
+    /// restore the entries from a snapshot, this replaces existing entries with the same key
+    /// entries keep the idle time they had when the snapshot was made, so stale entries are dropped
+    pub fn restore(&mut self, snapshot: CacheSnapshot<K, O>) {
+        let now = self.clock.now();
+        for snapshot_entry in snapshot.entries {
+            let last_used = now.checked_sub(snapshot_entry.idle).unwrap_or(now);
+            // the snapshot may come from an untrusted source, a ttl which overflows never expires
+            let expires_at = snapshot_entry.ttl.and_then(|ttl| now.checked_add(ttl));
+            // the snapshot only has the time left, so reloads use the ttl of the cache if it has one
+            let ttl = snapshot_entry.ttl.map(|left| self.ttl.unwrap_or(left));
+            self.track_expiry(expires_at);
+            self.remove_entry(&snapshot_entry.key, RemovalCause::Replaced);
+            let weight = self.weigh(&snapshot_entry.key, &snapshot_entry.item);
+            self.weighted_size += weight;
+            self.policy_on_insert(&snapshot_entry.key);
+            self.entries.insert(
+                snapshot_entry.key,
+                CacheEntry {
+                    item: snapshot_entry.item,
+                    last_used,
+                    loaded_at: now,
+                    expires_at,
+                    ttl,
+                    weight,
+                    frequency: 0,
+                },
+            );
+        }
+        self.evict_to_capacity();
+        self.remove_stale();
+    }
+}
+
+impl<K: std::cmp::Eq + std::hash::Hash + Clone, O, E> Cache<K, O, E> {
+    /// set the policy which decides which entry is evicted when the cache is full, see CachePolicy
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::{Cache, CacheIFace, CachePolicy};
+    /// use std::time::Duration;
+    /// let mut cache = Cache::new(|key: &i32| Some(*key), Duration::from_secs(60), 2)
+    ///     .with_policy(CachePolicy::Lfu);
+    /// cache.get(&1);
+    /// cache.get(&1);
+    /// cache.get(&2);
+    /// cache.get(&3);
+    /// // 2 was used less often than 1
+    /// assert!(cache.contains_key(&1));
+    /// assert!(!cache.contains_key(&2));
+    /// ```
+    pub fn with_policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = policy;
+        self.w_tiny_lfu = match policy {
+            CachePolicy::WTinyLfu => {
+                let mut state = WTinyLfu::new(self.max_size);
+                for key in self.entries.keys() {
+                    state.main.insert(key.clone(), ());
+                }
+                Some(state)
+            }
+            _ => None,
+        };
+        self
+    }
+
+    fn policy_on_insert(&mut self, key: &K) {
+        if let Some(state) = self.w_tiny_lfu.as_mut() {
+            state.on_insert(key);
+        }
+    }
+
+    fn policy_on_remove(&mut self, key: &K) {
+        if let Some(state) = self.w_tiny_lfu.as_mut() {
+            state.on_remove(key);
+        }
+    }
+
+    fn policy_on_access(&mut self, key: &K, hit: bool) {
+        match self.policy {
+            CachePolicy::Lru => {}
+            CachePolicy::Lfu => {
+                if let Some(entry) = self.entries.get_mut(key) {
+                    entry.frequency = entry.frequency.saturating_add(1);
+                }
+            }
+            CachePolicy::WTinyLfu => {
+                if let Some(state) = self.w_tiny_lfu.as_mut() {
+                    state.on_access(key, hit);
+                }
+            }
+        }
+    }
+
+    fn select_victim(&mut self) -> Option<K> {
+        match self.policy {
+            CachePolicy::Lru => self.entries.front().map(|(key, _)| key.clone()),
+            CachePolicy::Lfu => {
+                // skip the entry which was inserted last, else a new entry would always be the victim
+                let candidates = self.entries.len().saturating_sub(1).max(1);
+                self.entries
+                    .iter()
+                    .take(candidates)
+                    .min_by_key(|(_key, entry)| entry.frequency)
+                    .map(|(key, _)| key.clone())
+            }
+            CachePolicy::WTinyLfu => self
+                .w_tiny_lfu
+                .as_mut()
+                .and_then(|state| state.select_victim()),
+        }
+    }
+
+    /// insert an item which expires after ttl, this overrides the ttl of the cache for this entry
+    pub fn insert_with_ttl(&mut self, key: K, item: O, ttl: Duration) {
+        // an item which is heavier than max_weight is not cached
+        let _ = self.insert_entry(key, item, Some(ttl));
+    }
+
+    /// insert an entry, if it is heavier than max_weight it is not cached and the item is returned
+    fn insert_entry(&mut self, key: K, item: O, ttl: Option<Duration>) -> Option<O> {
+        let weight = self.weigh(&key, &item);
+        if weight > self.max_weight {
+            // it would not fit even in an empty cache, don't evict other entries for it
+            self.cancel_reload(&key);
+            self.remove_entry(&key, RemovalCause::Replaced);
+            self.negative_entries.remove(&key);
+            return Some(item);
+        }
+        let now = self.clock.now();
+        // a ttl which is too large to be represented never expires
+        let expires_at = ttl.and_then(|ttl| now.checked_add(ttl));
+        self.track_expiry(expires_at);
+        let entry = CacheEntry {
+            item,
+            last_used: now,
+            loaded_at: now,
+            expires_at,
+            ttl,
+            weight,
+            frequency: 0,
+        };
+        self.cancel_reload(&key);
+        self.remove_entry(&key, RemovalCause::Replaced);
+        self.negative_entries.remove(&key);
+        self.weighted_size += weight;
+        self.policy_on_insert(&key);
+        self.entries.insert(key, entry);
+        self.evict_to_capacity();
+        None
+    }
+
+    /// replace the item of an existing entry with a reloaded item, the entry keeps its position, frequency and ttl
+    fn replace_item(&mut self, key: K, item: O) {
+        let now = self.clock.now();
+        let weight = self.weigh(&key, &item);
+        if weight > self.max_weight {
+            // the reloaded item does not fit, drop the entry instead of evicting others
+            self.remove_entry(&key, RemovalCause::Capacity);
+            return;
+        }
+        let entry = match self.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let expires_at = entry.ttl.and_then(|ttl| now.checked_add(ttl));
+        let old_item = std::mem::replace(&mut entry.item, item);
+        let old_weight = std::mem::replace(&mut entry.weight, weight);
+        entry.loaded_at = now;
+        entry.expires_at = expires_at;
+        self.weighted_size = self.weighted_size - old_weight + weight;
+        self.track_expiry(expires_at);
+        self.notify_removal(key, old_item, RemovalCause::Replaced);
+        self.evict_to_capacity();
+    }
+
+    /// apply the results of reloads which completed since the last access
+    fn apply_reloads(&mut self) {
+        let reloaded: Vec<(K, Result<Option<O>, E>)> = match self.refresh.as_mut() {
+            Some(refresh) => {
+                let mut reloaded = vec![];
+                for (key, reload_id, res) in refresh.reloaded.try_iter() {
+                    // ignore reloads which were cancelled by an insert or invalidate
+                    if refresh.in_flight.get(&key) == Some(&reload_id) {
+                        refresh.in_flight.remove(&key);
+                        reloaded.push((key, res));
+                    }
+                }
+                reloaded
+            }
+            None => return,
+        };
+        for (key, res) in reloaded {
+            match res {
+                Ok(Some(item)) => self.replace_item(key, item),
+                Ok(None) => self.remove_entry(&key, RemovalCause::Expired),
+                // the error was logged by the reload task, keep the current item
+                Err(_e) => {}
+            }
+        }
+    }
+
+    /// start a reload in the background if the entry is older than refresh_after
+    fn refresh_if_due(&mut self, key: &K) {
+        let now = self.clock.now();
+        let refresh = match self.refresh.as_mut() {
+            Some(refresh) => refresh,
+            None => return,
+        };
+        let due = match self.entries.get(key) {
+            Some(entry) => now.saturating_duration_since(entry.loaded_at) >= refresh.refresh_after,
+            None => false,
+        };
+        if due && !refresh.in_flight.contains_key(key) {
+            let reload_id = refresh.next_reload_id;
+            refresh.next_reload_id += 1;
+            refresh.in_flight.insert(key.clone(), reload_id);
+            (refresh.spawn_reload)(key.clone(), reload_id);
+        }
+    }
+
+    /// make sure the result of a running reload of the key is not applied
+    fn cancel_reload(&mut self, key: &K) {
+        if let Some(refresh) = self.refresh.as_mut() {
+            refresh.in_flight.remove(key);
+        }
+    }
+
+    fn weigh(&self, key: &K, item: &O) -> usize {
+        match self.weigher.as_ref() {
+            Some(weigher) => weigher(key, item),
+            None => 1,
+        }
+    }
+
+    fn remove_entry(&mut self, key: &K, cause: RemovalCause) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.weighted_size -= entry.weight;
+            self.policy_on_remove(key);
+            self.notify_removal(key.clone(), entry.item, cause);
+        }
+    }
+
+    fn pop_front_entry(&mut self, cause: RemovalCause) {
+        if let Some((key, entry)) = self.entries.pop_front() {
+            self.weighted_size -= entry.weight;
+            self.policy_on_remove(&key);
+            self.notify_removal(key, entry.item, cause);
+        }
+    }
+
+    /// evict entries according to the policy until both the size and the weight are within their limits
+    fn evict_to_capacity(&mut self) {
+        while self.entries.len() > self.max_size || self.weighted_size > self.max_weight {
+            match self.select_victim() {
+                Some(key) => self.remove_entry(&key, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+    }
+
+    /// move the entry to the back of the LRU order if it was not used within the inactive_resolution
+    fn touch(&mut self, key: &K, now: Instant) {
+        let touch = match (
+            self.entries.get(key),
+            now.checked_sub(self.inactive_resolution),
+        ) {
+            (Some(e), Some(resolution_start)) => e.last_used.lt(&resolution_start),
+            _ => false,
+        };
+        if touch {
+            if let Some(e) = self.entries.get_refresh(key) {
+                e.last_used = now;
+            }
+        }
+    }
+
+    /// call the producer for a missing entry and cache the result
+    fn load(&mut self, key: &K) -> Result<(), E> {
+        let start = Instant::now();
+        let res = self.producer.call(key);
+        if let Some(stats) = self.stats.as_mut() {
+            stats.misses += 1;
+            stats.loads += 1;
+            stats.load_time += start.elapsed();
+            match &res {
+                Ok(Some(_item)) => {}
+                Ok(None) => stats.load_nones += 1,
+                Err(_e) => stats.load_errors += 1,
+            }
+        }
+        match res? {
+            Some(item) => {
+                let ttl = self.ttl;
+                self.uncached = self
+                    .insert_entry(key.clone(), item, ttl)
+                    .map(|item| (key.clone(), item));
+            }
+            None => {
+                if let Some(negative_ttl) = self.negative_ttl {
+                    let expires_at = self.clock.now().checked_add(negative_ttl);
+                    self.negative_entries.insert(key.clone(), expires_at);
+                    while self.negative_entries.len() > self.max_size {
+                        self.negative_entries.pop_front();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, key: &K) -> Result<(), E> {
+        self.uncached = None;
+        self.apply_reloads();
+        self.remove_stale();
+        if self.contains_live_key(key) || self.negative_entries.contains_key(key) {
+            if let Some(stats) = self.stats.as_mut() {
+                stats.hits += 1;
+            }
+            self.refresh_if_due(key);
+            Ok(())
+        } else {
+            self.load(key)
+        }
+    }
+
+    fn remove_stale(&mut self) {
+        let now = self.clock.now();
+        self.invalidate_stale_negative(now);
+
+        // entries with a ttl are not ordered by expiry so those need a full scan, only do that when one has expired
+        if matches!(self.next_expiry, Some(next_expiry) if next_expiry.le(&now)) {
+            let expired: Vec<K> = self
+                .entries
+                .iter()
+                .filter(|(_key, entry)| Self::is_expired(entry, now))
+                .map(|(key, _entry)| key.clone())
+                .collect();
+            for key in expired {
+                self.remove_entry(&key, RemovalCause::Expired);
+            }
+            self.next_expiry = self
+                .entries
+                .values()
+                .filter_map(|entry| entry.expires_at)
+                .min();
+        }
+
+        let max_age = match now.checked_sub(self.max_inactive_time) {
+            Some(max_age) => max_age,
+            None => return,
+        };
 
         loop {
             let front_opt: Option<(&K, &CacheEntry<O>)> = self.entries.front();
             if let Some(entry) = front_opt {
                 let e = entry.1;
                 if e.last_used.lt(&max_age) {
-                    let _drop_entry = self.entries.pop_front();
+                    self.pop_front_entry(RemovalCause::Expired);
                 } else {
                     break;
                 }
@@ -76,86 +977,314 @@ impl<K: std::cmp::Eq + std::hash::Hash + Clone, O> CacheIFace<K, O> for Cache<K,
         }
     }
 
-    fn opt(&mut self, key: &K) -> Option<&O> {
-        let entry_opt = self.entries.get_mut(key);
-        if let Some(e) = entry_opt {
-            let now = Instant::now();
-            // check if the entry falls outside the resolution , prevents entries being reinserted on every get
-            if e.last_used.lt(&now.sub(self.inactive_resolution)) {
-                let mut removed_entry = self.entries.remove(key).unwrap();
-                removed_entry.last_used = now;
-                self.entries.insert(key.clone(), removed_entry);
-            }
-            self.entries.get(key).map(|i| &i.item)
+    /// check if key is the uncached item of the last load
+    fn is_uncached(&self, key: &K) -> bool {
+        matches!(&self.uncached, Some((uncached_key, _item)) if uncached_key == key)
+    }
+
+    fn contains_live_key(&self, key: &K) -> bool {
+        let now = self.clock.now();
+        self.entries
+            .get(key)
+            .map(|e| !Self::is_expired(e, now))
+            .unwrap_or(false)
+    }
+
+    /// remove the negative entries which outlived the negative_ttl
+    fn invalidate_stale_negative(&mut self, now: Instant) {
+        while let Some((_key, expires_at)) = self.negative_entries.front() {
+            if matches!(expires_at, Some(expires_at) if expires_at.le(&now)) {
+                self.negative_entries.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// apply completed reloads and remove stale entries, this is what a sweeper does every tick
+    fn sweep(&mut self) {
+        self.apply_reloads();
+        self.remove_stale();
+    }
+
+    /// remove the entry if it is expired, returns true if the entry exists and is not expired
+    fn check_expired(&mut self, key: &K, now: Instant) -> bool {
+        match self.entries.get(key) {
+            Some(entry) if Self::is_expired(entry, now) => {
+                self.remove_entry(key, RemovalCause::Expired);
+                false
+            }
+            Some(_entry) => true,
+            None => false,
+        }
+    }
+}
+
+impl<K, O, E> Cache<K, O, E>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone + Send + 'static,
+    O: Send + 'static,
+    E: Send + 'static,
+{
+    /// reload entries in the background when they are older than refresh_after
+    ///
+    /// when get or get_mut finds an entry which was loaded more than refresh_after ago it returns the current item and
+    /// runs the producer in the TaskManager, if the producer returns None the entry is removed and if it fails the error
+    /// is logged and the current item is kept
+    /// the producer is shared with the TaskManager behind a lock, so a get which needs to call the producer while a
+    /// reload runs waits for the reload
+    ///
+    /// a completed reload replaces the item on the next access to the cache, or on the next tick of a sweeper (see
+    /// sweep_with_event_loop and sweep_with_task_manager)
+    /// the result of a reload which was started before the key was inserted or invalidated is ignored
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::Cache;
+    /// use hirofa_utils::task_manager::TaskManager;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    /// let cache = Cache::new(|key: &i32| Some(*key), Duration::from_secs(600), 10)
+    ///     .with_refresh_after(Duration::from_secs(60), Arc::new(TaskManager::new(1)));
+    /// ```
+    pub fn with_refresh_after(
+        mut self,
+        refresh_after: Duration,
+        task_manager: Arc<TaskManager>,
+    ) -> Self
+    where
+        E: Display,
+    {
+        let producer = match self.producer {
+            Producer::Owned(producer) => Arc::new(Mutex::new(producer)),
+            Producer::Shared(producer) => producer,
+        };
+        self.producer = Producer::Shared(producer.clone());
+        let (tx, rx) = channel();
+        let spawn_reload = move |key: K, reload_id: u64| {
+            let producer = producer.clone();
+            let tx = tx.clone();
+            task_manager.add_task(move || {
+                let res = (producer.lock())(&key);
+                if let Err(e) = res.as_ref() {
+                    log::error!("Cache refresh failed: {}", e);
+                }
+                // the cache may have been dropped
+                let _ = tx.send((key, reload_id, res));
+            });
+        };
+        self.refresh = Some(Refresh {
+            refresh_after,
+            in_flight: HashMap::new(),
+            next_reload_id: 0,
+            spawn_reload: Box::new(spawn_reload),
+            reloaded: rx,
+        });
+        self
+    }
+
+    /// remove stale entries from a shared cache and apply completed reloads every interval using an interval in an EventLoop
+    /// the sweeper only holds a weak reference to the cache and stops when the cache is dropped, it skips a tick when the
+    /// cache is locked
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::cache::Cache;
+    /// use hirofa_utils::eventloop::EventLoop;
+    /// use parking_lot::Mutex;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    /// let event_loop = EventLoop::new();
+    /// let cache = Arc::new(Mutex::new(Cache::new(
+    ///     |key: &i32| Some(*key),
+    ///     Duration::from_secs(60),
+    ///     10,
+    /// )));
+    /// Cache::sweep_with_event_loop(&cache, &event_loop, Duration::from_secs(10));
+    /// ```
+    pub fn sweep_with_event_loop(
+        cache: &Arc<Mutex<Self>>,
+        event_loop: &EventLoop,
+        interval: Duration,
+    ) {
+        let cache_weak: Weak<Mutex<Self>> = Arc::downgrade(cache);
+        event_loop.add_void(move || {
+            let interval_id = Rc::new(Cell::new(None));
+            let task_interval_id = interval_id.clone();
+            let id = EventLoop::add_interval(
+                move || match cache_weak.upgrade() {
+                    // don't block the EventLoop while the cache is in use (e.g. by a slow producer), skip this tick
+                    Some(cache) => {
+                        if let Some(mut cache) = cache.try_lock() {
+                            cache.sweep();
+                        }
+                    }
+                    None => {
+                        if let Some(id) = task_interval_id.get() {
+                            EventLoop::clear_interval(id);
+                        }
+                    }
+                },
+                interval,
+                interval,
+            );
+            interval_id.set(Some(id));
+        });
+    }
+
+    /// remove stale entries from a shared cache and apply completed reloads every interval using an async task in a
+    /// TaskManager
+    /// the sweeper only holds a weak reference to the cache and stops when the cache is dropped, it skips a tick when the
+    /// cache is locked
+    pub fn sweep_with_task_manager(
+        cache: &Arc<Mutex<Self>>,
+        task_manager: &TaskManager,
+        interval: Duration,
+    ) {
+        let cache_weak: Weak<Mutex<Self>> = Arc::downgrade(cache);
+        let _join_handle = task_manager.add_task_async(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            loop {
+                ticker.tick().await;
+                match cache_weak.upgrade() {
+                    // don't block a worker thread while the cache is in use, skip this tick
+                    Some(cache) => {
+                        if let Some(mut cache) = cache.try_lock() {
+                            cache.sweep();
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+impl<K: std::cmp::Eq + std::hash::Hash + Clone, O, E: Display> Cache<K, O, E> {
+    /// get an item, if it is not cached the producer is called, errors of the producer are returned and not cached
+    pub fn try_get(&mut self, key: &K) -> Result<Option<&O>, E> {
+        self.lookup(key)?;
+        if self.is_uncached(key) {
+            return Ok(self.uncached.as_ref().map(|(_key, item)| item));
+        }
+        Ok(self.opt(key))
+    }
+
+    /// get an item, if it is not cached the producer is called, errors of the producer are returned and not cached
+    pub fn try_get_mut(&mut self, key: &K) -> Result<Option<&mut O>, E> {
+        self.lookup(key)?;
+        if self.is_uncached(key) {
+            return Ok(self.uncached.as_mut().map(|(_key, item)| item));
+        }
+        Ok(self.opt_mut(key))
+    }
+}
+
+/// for a Cache created with new_fallible get and get_mut log errors of the producer and return None, use
+/// try_get or try_get_mut to get the error
+impl<K: std::cmp::Eq + std::hash::Hash + Clone, O, E: Display> CacheIFace<K, O> for Cache<K, O, E> {
+    fn invalidate_all(&mut self) {
+        if self.removal_listener.is_some() {
+            while let Some((key, entry)) = self.entries.pop_front() {
+                self.notify_removal(key, entry.item, RemovalCause::Cleared);
+            }
         } else {
-            None
+            if let Some(stats) = self.stats.as_mut() {
+                stats.record_removal(RemovalCause::Cleared, self.entries.len() as u64);
+            }
+            self.entries.clear();
+        }
+        self.next_expiry = None;
+        self.weighted_size = 0;
+        self.uncached = None;
+        self.negative_entries.clear();
+        if let Some(state) = self.w_tiny_lfu.as_mut() {
+            state.clear();
+        }
+        if let Some(refresh) = self.refresh.as_mut() {
+            refresh.in_flight.clear();
+        }
+    }
+
+    /// remove all entries which have been inactive for longer than max_inactive_time or which outlived their ttl
+    fn invalidate_stale(&mut self) {
+        self.remove_stale();
+    }
+
+    fn opt(&mut self, key: &K) -> Option<&O> {
+        self.apply_reloads();
+        let now = self.clock.now();
+        let hit = self.check_expired(key, now);
+        self.policy_on_access(key, hit);
+        if !hit {
+            return None;
         }
+        // only reorder entries which fall outside the resolution, prevents entries being reinserted on every get
+        self.touch(key, now);
+        self.entries.get(key).map(|i| &i.item)
     }
 
     fn opt_mut(&mut self, key: &K) -> Option<&mut O> {
-        let entry_opt = self.entries.get_mut(key);
-        if let Some(e) = entry_opt {
-            let now = Instant::now();
-            // check if the entry falls outside the resolution , prevents entries being reinserted on every get
-            if e.last_used.lt(&now.sub(self.inactive_resolution)) {
-                let mut removed_entry = self.entries.remove(key).unwrap();
-                removed_entry.last_used = now;
-                self.entries.insert(key.clone(), removed_entry);
-            }
-            self.entries.get_mut(key).map(|i| &mut i.item)
-        } else {
-            None
+        self.apply_reloads();
+        let now = self.clock.now();
+        let hit = self.check_expired(key, now);
+        self.policy_on_access(key, hit);
+        if !hit {
+            return None;
         }
+        // only reorder entries which fall outside the resolution, prevents entries being reinserted on every get
+        self.touch(key, now);
+        self.entries.get_mut(key).map(|i| &mut i.item)
     }
 
     fn opt_no_touch(&self, key: &K) -> Option<&O> {
-        self.entries.get(key).map(|e| &e.item)
+        let now = self.clock.now();
+        self.entries
+            .get(key)
+            .filter(|e| !Self::is_expired(e, now))
+            .map(|e| &e.item)
     }
 
     fn get(&mut self, key: &K) -> Option<&O> {
-        self.invalidate_stale();
-        if !self.contains_key(key) {
-            let item_opt = (*self.producer)(key);
-            if let Some(item) = item_opt {
-                self.insert(key.clone(), item);
-            }
+        if let Err(e) = self.lookup(key) {
+            log::error!("Cache producer failed: {}", e);
+        }
+        if self.is_uncached(key) {
+            return self.uncached.as_ref().map(|(_key, item)| item);
         }
         self.opt(key)
     }
 
     fn get_mut(&mut self, key: &K) -> Option<&mut O> {
-        self.invalidate_stale();
-        if !self.contains_key(key) {
-            let item_opt = (*self.producer)(key);
-            if let Some(item) = item_opt {
-                self.insert(key.clone(), item);
-            }
+        if let Err(e) = self.lookup(key) {
+            log::error!("Cache producer failed: {}", e);
+        }
+        if self.is_uncached(key) {
+            return self.uncached.as_mut().map(|(_key, item)| item);
         }
         self.opt_mut(key)
     }
     fn contains_key(&self, key: &K) -> bool {
-        self.entries.contains_key(key)
+        self.contains_live_key(key)
     }
 
     fn invalidate(&mut self, key: &K) {
-        self.entries.remove(key);
+        self.cancel_reload(key);
+        self.negative_entries.remove(key);
+        self.remove_entry(key, RemovalCause::Explicit);
     }
 
     fn insert(&mut self, key: K, item: O) {
-        let entry = CacheEntry {
-            item,
-            last_used: Instant::now(),
-        };
-        self.entries.insert(key, entry);
-        while self.entries.len() > self.max_size {
-            let _drop_entry = self.entries.pop_front();
-        }
+        let ttl = self.ttl;
+        let _ = self.insert_entry(key, item, ttl);
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::cache::{Cache, CacheIFace};
+    use crate::cache::{Cache, CacheIFace, CachePolicy, CacheStats, ManualClock, RemovalCause};
+    use crate::eventloop::EventLoop;
+    use crate::task_manager::TaskManager;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
     fn test_send<S: Send>(_sendable: &S) {
@@ -189,4 +1318,389 @@ pub mod tests {
 
         assert_eq!(10, cache.len());
     }
+
+    #[test]
+    fn test_snapshot() {
+        let producer = |key: &String| Some(format!("entry: {key}"));
+        let mut cache: Cache<String, String> = Cache::new(producer, Duration::from_secs(60), 10);
+        for x in ["a", "b", "c"] {
+            let _ = cache.get(&x.to_string());
+        }
+
+        let json = serde_json::to_string(&cache.snapshot()).expect("serialize failed");
+
+        let mut restored: Cache<String, String> =
+            Cache::new(|_key: &String| None, Duration::from_secs(60), 10);
+        restored.restore(serde_json::from_str(&json).expect("deserialize failed"));
+        assert_eq!(restored.len(), 3);
+        assert_eq!(
+            restored.get(&"b".to_string()).map(|s| s.as_str()),
+            Some("entry: b")
+        );
+
+        // a snapshot with a ttl which overflows does not panic
+        restored.restore(
+            serde_json::from_str(
+                r#"{"entries":[{"key":"d","item":"dee","idle":{"secs":0,"nanos":0},"ttl":{"secs":18446744073709551615,"nanos":0}}]}"#,
+            )
+            .expect("deserialize failed"),
+        );
+        assert!(restored.contains_key(&"d".to_string()));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = ManualClock::new();
+        let producer = |key: &&str| Some(format!("entry: {key}"));
+        let mut cache: Cache<&str, String> = Cache::new(producer, Duration::from_secs(10), 10)
+            .with_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone());
+
+        let _ = cache.get(&"a");
+        cache.insert_with_ttl("b", "bee".to_string(), Duration::from_secs(2));
+        cache.insert_with_ttl("c", "sea".to_string(), Duration::from_secs(60));
+
+        // b outlived its ttl
+        clock.advance(Duration::from_secs(3));
+        assert!(!cache.contains_key(&"b"));
+        assert_eq!(cache.opt(&"a").map(|s| s.as_str()), Some("entry: a"));
+        cache.invalidate_stale();
+        assert_eq!(cache.len(), 2);
+
+        // a expires after the ttl of the cache, even though it was used
+        clock.advance(Duration::from_secs(3));
+        assert!(cache.opt(&"a").is_none());
+        assert_eq!(cache.opt(&"c").map(|s| s.as_str()), Some("sea"));
+
+        // c is inactive for too long even though its ttl has not passed
+        clock.advance(Duration::from_secs(11));
+        cache.invalidate_stale();
+        assert!(cache.is_empty());
+
+        // get reloads expired entries
+        let _ = cache.get(&"a");
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"a").map(|s| s.as_str()), Some("entry: a"));
+        assert_eq!(cache.len(), 1);
+
+        // a ttl which overflows never expires
+        cache.insert_with_ttl("d", "dee".to_string(), Duration::MAX);
+        assert!(cache.contains_key(&"d"));
+        let mut cache: Cache<&str, String> = Cache::new(producer, Duration::from_secs(10), 10)
+            .with_ttl(Duration::MAX)
+            .with_clock(clock.clone());
+        assert_eq!(cache.get(&"a").map(|s| s.as_str()), Some("entry: a"));
+        clock.advance(Duration::from_secs(5));
+        assert!(cache.contains_key(&"a"));
+    }
+
+    #[test]
+    fn test_weigher() {
+        let producer = |key: &usize| Some(vec![0u8; *key]);
+        let mut cache: Cache<usize, Vec<u8>> = Cache::new(producer, Duration::from_secs(60), 100)
+            .with_weigher(100, |_key, item: &Vec<u8>| item.len());
+
+        for size in [10, 20, 30] {
+            let _ = cache.get(&size);
+        }
+        assert_eq!(cache.weighted_size(), 60);
+
+        // evicts 10 to make room for 50
+        let _ = cache.get(&50);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.weighted_size(), 100);
+        assert!(!cache.contains_key(&10));
+
+        // replacing an entry replaces its weight
+        cache.insert(30, vec![0u8; 5]);
+        assert_eq!(cache.weighted_size(), 75);
+
+        cache.invalidate(&50);
+        assert_eq!(cache.weighted_size(), 25);
+
+        // an entry heavier than the budget is returned but not cached, and nothing is evicted for it
+        assert_eq!(cache.get(&150).map(|item| item.len()), Some(150));
+        assert!(!cache.contains_key(&150));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.weighted_size(), 25);
+        cache.insert(20, vec![0u8; 150]);
+        assert!(!cache.contains_key(&20));
+        assert_eq!(cache.weighted_size(), 5);
+    }
+
+    #[test]
+    fn test_removal_listener() {
+        let clock = ManualClock::new();
+        let removed = Arc::new(Mutex::new(vec![]));
+        let listener_removed = removed.clone();
+        let producer = |key: &&str| Some(format!("entry: {key}"));
+        let mut cache: Cache<&str, String> = Cache::new(producer, Duration::from_secs(10), 2)
+            .with_clock(clock.clone())
+            .with_removal_listener(move |key, _item, cause| {
+                listener_removed.lock().unwrap().push((key, cause));
+            });
+
+        let _ = cache.get(&"a");
+        let _ = cache.get(&"b");
+        cache.insert("b", "bee".to_string());
+        let _ = cache.get(&"c");
+        cache.invalidate(&"b");
+        cache.insert_with_ttl("d", "dee".to_string(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(2));
+        cache.invalidate_stale();
+        let _ = cache.get(&"e");
+        cache.invalidate_all();
+
+        assert_eq!(
+            *removed.lock().unwrap(),
+            vec![
+                ("b", RemovalCause::Replaced),
+                ("a", RemovalCause::Capacity),
+                ("b", RemovalCause::Explicit),
+                ("d", RemovalCause::Expired),
+                ("c", RemovalCause::Cleared),
+                ("e", RemovalCause::Cleared),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policies() {
+        let producer = |key: &usize| Some(*key);
+
+        let mut cache =
+            Cache::new(producer, Duration::from_secs(60), 3).with_policy(CachePolicy::Lfu);
+        for (key, uses) in [(1, 3), (2, 1), (3, 2)] {
+            for _ in 0..uses {
+                cache.get(&key);
+            }
+        }
+        cache.get(&4);
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.len(), 3);
+
+        // hot keys survive a scan with WTinyLfu but not with Lru
+        for (policy, expected_hot) in [(CachePolicy::Lru, 0..10), (CachePolicy::WTinyLfu, 45..51)] {
+            let mut cache = Cache::new(producer, Duration::from_secs(60), 100).with_policy(policy);
+            for _ in 0..5 {
+                for key in 0..50 {
+                    cache.get(&key);
+                }
+            }
+            for key in 1000..1300 {
+                cache.get(&key);
+            }
+            let hot = (0..50).filter(|key| cache.contains_key(key)).count();
+            assert!(
+                expected_hot.contains(&hot),
+                "{:?} kept {} hot keys",
+                policy,
+                hot
+            );
+            assert_eq!(cache.len(), 100);
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let producer = |key: &usize| if *key < 10 { Some(*key) } else { None };
+        let mut cache = Cache::new(producer, Duration::from_secs(60), 2).with_stats();
+
+        cache.get(&1);
+        cache.get(&1);
+        cache.get(&2);
+        cache.get(&3);
+        cache.get(&11);
+        cache.insert(3, 33);
+        cache.invalidate(&3);
+        cache.invalidate_all();
+
+        let stats = cache.stats().expect("no stats");
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.loads, 4);
+        assert_eq!(stats.load_nones, 1);
+        assert_eq!(stats.evicted_capacity, 1);
+        assert_eq!(stats.removed_replaced, 1);
+        assert_eq!(stats.removed_explicit, 1);
+        assert_eq!(stats.removed_cleared, 1);
+        assert_eq!(stats.hit_ratio(), 0.2);
+
+        // the average does not overflow for large numbers of loads
+        let many = CacheStats {
+            loads: 1 << 32,
+            load_time: Duration::from_secs(1 << 32),
+            ..Default::default()
+        };
+        assert_eq!(many.average_load_time(), Duration::from_secs(1));
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), Some(Default::default()));
+        assert!(Cache::new(producer, Duration::from_secs(60), 2)
+            .stats()
+            .is_none());
+    }
+
+    #[test]
+    fn test_fallible() {
+        let clock = ManualClock::new();
+        let loads = Arc::new(Mutex::new(0));
+        let producer_loads = loads.clone();
+        let producer = move |key: &i32| {
+            *producer_loads.lock().unwrap() += 1;
+            match key {
+                0 => Err("zero"),
+                1 => Ok(None),
+                _ => Ok(Some(key * 2)),
+            }
+        };
+        let mut cache = Cache::new_fallible(producer, Duration::from_secs(60), 10)
+            .with_negative_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone())
+            .with_stats();
+
+        // errors are propagated and not cached
+        assert_eq!(cache.try_get(&0), Err("zero"));
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(*loads.lock().unwrap(), 2);
+
+        // None is cached for the negative ttl
+        assert_eq!(cache.try_get(&1), Ok(None));
+        assert_eq!(cache.try_get(&1), Ok(None));
+        assert_eq!(*loads.lock().unwrap(), 3);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.try_get(&1), Ok(None));
+        assert_eq!(*loads.lock().unwrap(), 4);
+
+        // inserting replaces a negative entry
+        cache.insert(1, 11);
+        assert_eq!(cache.try_get(&1), Ok(Some(&11)));
+        assert_eq!(cache.try_get_mut(&2).map(|item| item.copied()), Ok(Some(4)));
+
+        let stats = cache.stats().expect("no stats");
+        assert_eq!(stats.load_errors, 2);
+        assert_eq!(stats.load_nones, 2);
+        assert_eq!(stats.hits, 2);
+
+        // a negative_ttl which overflows never expires
+        let mut cache = Cache::new(|_key: &i32| None::<i32>, Duration::from_secs(60), 10)
+            .with_negative_ttl(Duration::MAX)
+            .with_clock(clock.clone())
+            .with_stats();
+        assert_eq!(cache.get(&1), None);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.stats().map(|stats| stats.loads), Some(1));
+    }
+
+    #[test]
+    fn test_sweeper() {
+        let event_loop = EventLoop::new();
+        let task_manager = TaskManager::new(1);
+        for use_event_loop in [true, false] {
+            let clock = ManualClock::new();
+            let cache = Arc::new(parking_lot::Mutex::new(
+                Cache::new(|key: &i32| Some(*key), Duration::from_secs(60), 10)
+                    .with_ttl(Duration::from_secs(1))
+                    .with_clock(clock.clone()),
+            ));
+            if use_event_loop {
+                Cache::sweep_with_event_loop(&cache, &event_loop, Duration::from_millis(10));
+            } else {
+                Cache::sweep_with_task_manager(&cache, &task_manager, Duration::from_millis(10));
+            }
+            cache.lock().get(&1);
+
+            // while the cache is locked ticks are skipped instead of blocking the EventLoop
+            {
+                let _locked = cache.lock();
+                std::thread::sleep(Duration::from_millis(50));
+                assert_eq!(event_loop.exe(|| 1), 1);
+            }
+
+            clock.advance(Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(cache.lock().is_empty());
+
+            // the sweeper does not keep the cache alive
+            assert_eq!(Arc::strong_count(&cache), 1);
+            assert_eq!(Arc::weak_count(&cache), 1);
+            drop(cache);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_refresh_after() {
+        let clock = ManualClock::new();
+        let loads = Arc::new(Mutex::new(0));
+        let producer_loads = loads.clone();
+        let producer = move |key: &i32| {
+            // a slow producer so get returns before a reload completes
+            std::thread::sleep(Duration::from_millis(50));
+            let loads = &mut *producer_loads.lock().unwrap();
+            *loads += 1;
+            Some(key * 100 + *loads)
+        };
+        let task_manager = Arc::new(TaskManager::new(1));
+        let mut cache = Cache::new(producer, Duration::from_secs(60), 10)
+            .with_clock(clock.clone())
+            .with_refresh_after(Duration::from_secs(10), task_manager.clone());
+
+        assert_eq!(cache.get(&1), Some(&101));
+        clock.advance(Duration::from_secs(11));
+
+        // the current value is returned while the reload runs in the background
+        assert_eq!(cache.get(&1), Some(&101));
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(*loads.lock().unwrap(), 2);
+        assert_eq!(cache.get(&1), Some(&102));
+
+        // the reloaded entry is fresh again
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.get(&1), Some(&102));
+        assert_eq!(*loads.lock().unwrap(), 2);
+
+        // opt applies a completed reload too
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(cache.get(&1), Some(&102));
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.opt(&1), Some(&103));
+
+        // a reload which was started before an insert does not overwrite the inserted item
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(cache.get(&1), Some(&103));
+        cache.insert(1, 7);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.get(&1), Some(&7));
+
+        // or an invalidate
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(cache.get(&1), Some(&7));
+        cache.invalidate(&1);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.opt(&1), None);
+
+        // a reloaded entry keeps its own ttl
+        cache.insert_with_ttl(2, 2, Duration::from_secs(20));
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(cache.get(&2), Some(&2));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(cache.opt(&2).is_some());
+        clock.advance(Duration::from_secs(21));
+        assert_eq!(cache.opt(&2), None);
+
+        // a sweeper applies completed reloads without an access to the cache
+        let removed = Arc::new(Mutex::new(vec![]));
+        let listener_removed = removed.clone();
+        let cache = Arc::new(parking_lot::Mutex::new(cache.with_removal_listener(
+            move |key, item, _cause| listener_removed.lock().unwrap().push((key, item)),
+        )));
+        Cache::sweep_with_task_manager(&cache, &task_manager, Duration::from_millis(10));
+        assert!(cache.lock().get(&3).is_some());
+        clock.advance(Duration::from_secs(11));
+        let item = cache.lock().get(&3).copied();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(removed.lock().unwrap().as_slice(), &[(3, item.unwrap())]);
+    }
 }
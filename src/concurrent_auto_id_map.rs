@@ -0,0 +1,206 @@
+use crate::auto_id_map::{id_bits_for_max_size, AutoIdMap, InsertError};
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// ConcurrentAutoIdMap is a thread safe AutoIdMap which may be shared between threads
+///
+/// ids are spread over RwLock'ed shards, reads of the same shard don't block each other
+/// elements are stored as Arc's so get can return a clone of the element without holding a lock
+/// # Example
+/// ```rust
+/// use hirofa_utils::concurrent_auto_id_map::ConcurrentAutoIdMap;
+/// use std::sync::Arc;
+/// let map = Arc::new(ConcurrentAutoIdMap::new());
+/// let m = map.clone();
+/// let id = std::thread::spawn(move || m.insert("hi")).join().unwrap();
+/// assert_eq!(*map.get(&id).unwrap(), "hi");
+/// assert_eq!(*map.remove(&id).unwrap(), "hi");
+/// assert!(map.get(&id).is_none());
+/// ```
+pub struct ConcurrentAutoIdMap<T> {
+    max_size: usize,
+    len: AtomicUsize,
+    next_shard: AtomicUsize,
+    shard_bits: u32,
+    shards: Vec<RwLock<AutoIdMap<Arc<T>>>>,
+}
+
+const MAX_SHARD_BITS: u32 = 6;
+
+/// the amount of bits used to select a shard of a sharded map or cache with this max_size
+/// this gives about 4 shards per cpu but never more shards than entries
+pub(crate) fn shard_bits_for_max_size(max_size: usize) -> u32 {
+    ((num_cpus::get() * 4).next_power_of_two().trailing_zeros())
+        .min(MAX_SHARD_BITS)
+        .min(usize::BITS - max_size.saturating_sub(1).leading_zeros())
+}
+
+impl<T> ConcurrentAutoIdMap<T> {
+    /// create a new instance of the ConcurrentAutoIdMap
+    pub fn new() -> Self {
+        Self::new_with_max_size(usize::MAX)
+    }
+
+    /// create a new instance of the ConcurrentAutoIdMap with a maximum number of entries
+    pub fn new_with_max_size(max_size: usize) -> Self {
+        let shard_bits = shard_bits_for_max_size(max_size);
+        let shard_count = 1 << shard_bits;
+        let shard_max_size = max_size.div_ceil(shard_count);
+        // the shard is encoded in the lower bits of the id, the ids of the inner maps use the rest
+        let inner_id_bits = id_bits_for_max_size(max_size) - shard_bits;
+        let shards = (0..shard_count)
+            .map(|_| {
+                RwLock::new(AutoIdMap::new_keyed_with_id_bits(
+                    shard_max_size,
+                    inner_id_bits,
+                ))
+            })
+            .collect();
+        Self {
+            max_size,
+            len: AtomicUsize::new(0),
+            next_shard: AtomicUsize::new(0),
+            shard_bits,
+            shards,
+        }
+    }
+
+    fn shard_of(&self, id: usize) -> (usize, usize) {
+        (id & ((1 << self.shard_bits) - 1), id >> self.shard_bits)
+    }
+
+    /// insert an element and return the new id
+    pub fn insert(&self, elem: T) -> usize {
+        self.try_insert(elem).expect("map is full")
+    }
+
+    /// insert an element and return the new id
+    pub fn try_insert(&self, elem: T) -> Result<usize, InsertError<T>> {
+        // reserve room for the element
+        if self
+            .len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                if len < self.max_size {
+                    Some(len + 1)
+                } else {
+                    None
+                }
+            })
+            .is_err()
+        {
+            return Err(InsertError::new(elem));
+        }
+
+        let mut elem = Arc::new(elem);
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        for x in 0..self.shards.len() {
+            let shard = (start + x) & (self.shards.len() - 1);
+            match self.shards[shard].write().try_insert(elem) {
+                Ok(inner_id) => return Ok((inner_id << self.shard_bits) | shard),
+                Err(e) => elem = e.into_inner(),
+            }
+        }
+        // the max_size is not evenly divisible over the shards
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Err(InsertError::new(
+            Arc::try_unwrap(elem).ok().expect("elem was shared"),
+        ))
+    }
+
+    /// get a clone of an element based on its id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn get(&self, id: &usize) -> Option<Arc<T>> {
+        let (shard, inner_id) = self.shard_of(*id);
+        self.shards[shard].read().get(&inner_id).cloned()
+    }
+
+    /// run a closure with a reference to an element
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, id: &usize, f: F) -> Option<R> {
+        let (shard, inner_id) = self.shard_of(*id);
+        self.shards[shard].read().get(&inner_id).map(|elem| f(elem))
+    }
+
+    /// remove an element based on its id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn remove(&self, id: &usize) -> Option<Arc<T>> {
+        let (shard, inner_id) = self.shard_of(*id);
+        let res = self.shards[shard].write().remove_opt(&inner_id);
+        if res.is_some() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        res
+    }
+
+    /// check if a map contains a certain id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn contains_key(&self, id: &usize) -> bool {
+        let (shard, inner_id) = self.shard_of(*id);
+        self.shards[shard].read().contains_key(&inner_id)
+    }
+
+    /// remove all elements
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let map = &mut *shard.write();
+            self.len.fetch_sub(map.len(), Ordering::SeqCst);
+            map.clear();
+        }
+    }
+
+    /// get the size of the map
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// see if map is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for ConcurrentAutoIdMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::concurrent_auto_id_map::ConcurrentAutoIdMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_caim() {
+        let map = Arc::new(ConcurrentAutoIdMap::new_with_max_size(1000));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    let mut ids = vec![];
+                    for x in 0..250 {
+                        let id = map.insert(t * 1000 + x);
+                        assert_eq!(*map.get(&id).unwrap(), t * 1000 + x);
+                        ids.push(id);
+                    }
+                    ids
+                })
+            })
+            .collect();
+        let ids: Vec<usize> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+
+        assert_eq!(map.len(), 1000);
+        assert!(map.try_insert(1).is_err());
+
+        for id in &ids {
+            assert!(*id <= i32::MAX as usize);
+            assert!(map.remove(id).is_some());
+            assert!(!map.contains_key(id));
+        }
+        assert!(map.is_empty());
+    }
+}
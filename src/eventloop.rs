@@ -1,14 +1,21 @@
-use crate::auto_id_map::AutoIdMap;
+use crate::affinity;
+use crate::auto_id_map::{AutoIdMap, Key};
+use crate::new_key_type;
 use futures::executor::{LocalPool, LocalSpawner};
 use futures::task::{LocalSpawnExt, SpawnExt};
 use lazy_static::lazy_static;
-use std::cell::RefCell;
+use parking_lot::Mutex;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::fmt::Formatter;
 use std::future::Future;
 use std::ops::Add;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
@@ -22,44 +29,203 @@ fn next_id() -> usize {
 
 /// the EventLoop struct is a single thread event queue
 pub struct EventLoop {
+    inner: Arc<EventLoopInner>,
+}
+
+/// a cheap, cloneable handle to an EventLoop
+/// the EventLoop keeps running as long as the EventLoop or any of its EventLoopHandles exist
+#[derive(Clone)]
+pub struct EventLoopHandle {
+    inner: Arc<EventLoopInner>,
+}
+
+/// a weak handle to an EventLoop, this does not keep the EventLoop alive
+/// submitting tasks will fail with an Err once the EventLoop has been dropped
+#[derive(Clone)]
+pub struct WeakEventLoopHandle {
+    inner: Weak<EventLoopInner>,
+}
+
+struct EventLoopInner {
     tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
     join_handle: Option<JoinHandle<()>>,
     id: usize,
+    cpu_affinity: Option<Vec<usize>>,
+    niceness: Option<i32>,
+    recording: Option<Arc<Mutex<Vec<RecordedTurn>>>>,
+}
+
+/// builder for an EventLoop with custom worker thread settings
+/// # Example
+/// ```rust
+/// use hirofa_utils::eventloop::EventLoopBuilder;
+/// let event_loop = EventLoopBuilder::new().cpu_affinity(vec![0]).build();
+/// assert_eq!(event_loop.exe(|| 1), 1);
+/// ```
+#[derive(Default)]
+pub struct EventLoopBuilder {
+    cpu_affinity: Option<Vec<usize>>,
+    niceness: Option<i32>,
+    turn_budget: Option<Duration>,
+    record: bool,
+    replay: Option<Vec<RecordedTurn>>,
+}
+
+/// a snapshot of information about an EventLoop
+#[derive(Debug, Clone)]
+pub struct EventLoopStats {
+    pub id: usize,
+    /// the cores the worker thread may run on, None if it could not be determined
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// the niceness of the worker thread, None if it could not be determined
+    pub niceness: Option<i32>,
+}
+
+/// a timeout or interval which fired during a turn of the EventLoop
+/// timers are identified by the order in which they were created in the EventLoop (starting at 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerEvent {
+    Timeout(usize),
+    Interval(usize),
+}
+
+/// a turn of the EventLoop as recorded by an EventLoop in record mode
+/// a turn first runs an optional task, then all futures until they stall, then fires timers and finally wakes futures which yielded
+/// please note that only wakes of futures which yielded (EventLoop::yield_now) are recorded, futures which are woken from
+/// another thread (e.g. a ResolvableFuture which is resolved in a TaskManager) are polled in whichever turn runs next, so
+/// that ordering is not reproduced by a replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTurn {
+    /// the time at which the turn started, relative to the start of the EventLoop
+    pub at: Duration,
+    /// a task which was submitted to the EventLoop was received in this turn
+    pub task: bool,
+    /// timers which fired in this turn, in order
+    pub timers: Vec<TimerEvent>,
+    /// futures which yielded with EventLoop::yield_now or EventLoop::yield_if_over_budget were woken at the end of this turn
+    pub woke: bool,
+}
+
+enum ScheduleMode {
+    Normal,
+    Record(Arc<Mutex<Vec<RecordedTurn>>>),
+    Replay(VecDeque<RecordedTurn>),
+}
+
+new_key_type! {
+    struct TimeoutId;
+    struct IntervalId;
 }
 
 struct Timeout {
+    seq: usize,
     next_run: Instant,
     task: Box<dyn FnOnce()>,
 }
 
 struct Interval {
+    seq: usize,
     next_run: Instant,
     interval: Duration,
     task: Rc<dyn Fn()>,
 }
 
 thread_local! {
-    static TIMEOUTS: RefCell<AutoIdMap<Timeout>> = RefCell::new(AutoIdMap::new_with_max_size(i32::MAX as usize));
-    static INTERVALS: RefCell<AutoIdMap<Interval>> = RefCell::new(AutoIdMap::new_with_max_size(i32::MAX as usize));
+    static TIMEOUTS: RefCell<AutoIdMap<Timeout, TimeoutId>> = RefCell::new(AutoIdMap::new_keyed_with_max_size(MAX_TIMERS));
+    static INTERVALS: RefCell<AutoIdMap<Interval, IntervalId>> = RefCell::new(AutoIdMap::new_keyed_with_max_size(MAX_TIMERS));
     // impl timeout and interval tasks as two separate thread_locals, add a single method to add jobs for timeouts and intervals which returns a next)runt instant, that may be used for recv on next loop
     static POOL: RefCell<LocalPool> = RefCell::new(LocalPool::new());
     static SPAWNER: RefCell<Option<LocalSpawner>> = const { RefCell::new(None) };
     static LOCAL_ID: RefCell<Option<usize>> = const { RefCell::new(None) };
+    // wakers of futures which yielded, these are woken after timeouts and intervals have run
+    static YIELDED: RefCell<Vec<Waker>> = const { RefCell::new(vec![]) };
+    static TURN_START: Cell<Option<Instant>> = const { Cell::new(None) };
+    static TURN_BUDGET: Cell<Duration> = const { Cell::new(DEFAULT_TURN_BUDGET) };
+    // virtual time of the loop in replay mode
+    static VIRTUAL_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+    static TIMER_SEQ: Cell<usize> = const { Cell::new(0) };
+}
+
+/// the max number of pending timeouts (and intervals) per EventLoop, this keeps the ids within the range of an i32
+const MAX_TIMERS: usize = 1 << 20;
+
+const DEFAULT_TURN_BUDGET: Duration = Duration::from_millis(10);
+
+/// future returned by EventLoop::yield_now and EventLoop::yield_if_over_budget
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            // don't wake right away, that would make run_until_stalled poll us again in the same turn
+            YIELDED.with(|rc| rc.borrow_mut().push(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
 }
 
 impl EventLoop {
     /// init a new EventLoop
     pub fn new() -> Self {
+        EventLoopBuilder::new().build()
+    }
+
+    /// create a builder for an EventLoop with custom settings
+    pub fn builder() -> EventLoopBuilder {
+        EventLoopBuilder::new()
+    }
+
+    fn new_from_builder(builder: EventLoopBuilder) -> Self {
         // todo settable buffer size
         let (tx, rx) = channel();
+        let (init_tx, init_rx) = channel();
 
         let id = next_id();
 
+        let EventLoopBuilder {
+            cpu_affinity,
+            niceness,
+            turn_budget,
+            record,
+            replay,
+        } = builder;
+
+        let recording = if record {
+            Some(Arc::new(Mutex::new(vec![])))
+        } else {
+            None
+        };
+        let mode = if let Some(replay) = replay {
+            ScheduleMode::Replay(replay.into())
+        } else if let Some(recording) = recording.as_ref() {
+            ScheduleMode::Record(recording.clone())
+        } else {
+            ScheduleMode::Normal
+        };
+
         let join_handle = std::thread::spawn(move || {
             LOCAL_ID.with(|rc| {
                 rc.borrow_mut().replace(id);
             });
 
+            affinity::apply_to_current_thread(cpu_affinity.as_deref(), niceness);
+            if let Some(turn_budget) = turn_budget {
+                TURN_BUDGET.with(|c| c.set(turn_budget));
+            }
+            init_tx
+                .send((
+                    affinity::current_thread_affinity(),
+                    affinity::current_thread_niceness(),
+                ))
+                .expect("send failed");
+
             POOL.with(|rc| {
                 let pool = &mut *rc.borrow_mut();
 
@@ -69,59 +235,177 @@ impl EventLoop {
                 });
             });
 
-            POOL.with(|rc| {
-                let pool = &mut *rc.borrow_mut();
-                let spawner = pool.spawner();
-                let mut next_deadline = Instant::now().add(Duration::from_secs(10));
-                loop {
-                    // recv may fail on timeout
-                    let recv_res = rx.recv_timeout(next_deadline.duration_since(Instant::now()));
-                    if recv_res.is_ok() {
-                        let fut: Box<dyn FnOnce() + Send + 'static> = recv_res.ok().unwrap();
-                        // this seems redundant.. i could just run the task closure
+            Self::run_worker(rx, mode);
+        });
+
+        let (cpu_affinity, niceness) = init_rx.recv().expect("EventLoop init failed");
+
+        Self {
+            inner: Arc::new(EventLoopInner {
+                tx,
+                join_handle: Some(join_handle),
+                id,
+                cpu_affinity,
+                niceness,
+                recording,
+            }),
+        }
+    }
 
-                        spawner.spawn(async move { fut() }).expect("spawn failed");
+    fn run_worker(rx: Receiver<Box<dyn FnOnce() + Send + 'static>>, mut mode: ScheduleMode) {
+        let start = Instant::now();
+        if let ScheduleMode::Replay(_) = mode {
+            VIRTUAL_NOW.with(|c| c.set(Some(start)));
+        }
+
+        POOL.with(|rc| {
+            let pool = &mut *rc.borrow_mut();
+            let spawner = pool.spawner();
+            let mut next_deadline = Instant::now().add(Duration::from_secs(10));
+            let mut prev_woke = false;
+            // the virtual and wall clock time at which the replay was exhausted
+            let mut replay_end: Option<(Instant, Instant)> = None;
+            loop {
+                let replay_turn = match &mut mode {
+                    ScheduleMode::Replay(turns) => turns.pop_front(),
+                    _ => None,
+                };
+
+                let recv_res = if let Some(turn) = replay_turn.as_ref() {
+                    VIRTUAL_NOW.with(|c| c.set(Some(start.add(turn.at))));
+                    if turn.task {
+                        // wait for the next task, however long it takes
+                        rx.recv().ok()
+                    } else {
+                        None
                     }
+                } else if let Some(virtual_now) = VIRTUAL_NOW.with(|c| c.get()) {
+                    // replay is done, the virtual clock follows the wall clock from here on
+                    let (virtual_end, real_end) =
+                        *replay_end.get_or_insert_with(|| (virtual_now, Instant::now()));
+                    let recv_res = if prev_woke {
+                        rx.try_recv().ok()
+                    } else {
+                        rx.recv_timeout(next_deadline.saturating_duration_since(virtual_now))
+                            .ok()
+                    };
+                    VIRTUAL_NOW.with(|c| c.set(Some(virtual_end.add(real_end.elapsed()))));
+                    recv_res
+                } else {
+                    // recv may fail on timeout
+                    rx.recv_timeout(next_deadline.duration_since(Instant::now()))
+                        .ok()
+                };
 
-                    pool.run_until_stalled();
+                // only keep track of the turn when recording
+                let mut turn = match &mode {
+                    ScheduleMode::Record(_) => Some(RecordedTurn {
+                        at: Self::now().duration_since(start),
+                        task: recv_res.is_some(),
+                        timers: vec![],
+                        woke: false,
+                    }),
+                    _ => None,
+                };
+
+                if let Some(fut) = recv_res {
+                    // this seems redundant.. i could just run the task closure
+                    spawner.spawn(async move { fut() }).expect("spawn failed");
+                }
+
+                TURN_START.with(|c| c.set(Some(Instant::now())));
+
+                pool.run_until_stalled();
+
+                // add jobs for timeout and interval here, recalc next timout deadline based on next pending timeout or interval
+                next_deadline = if let Some(replay_turn) = replay_turn.as_ref() {
+                    Self::replay_timeouts_and_intervals(&replay_turn.timers)
+                } else {
+                    Self::run_timeouts_and_intervals(turn.as_mut().map(|turn| &mut turn.timers))
+                };
 
-                    // add jobs for timeout and interval here, recalc next timout deadline based on next pending timeout or interval
-                    next_deadline = Self::run_timeouts_and_intervals();
-
-                    // shutdown indicator
-                    if SPAWNER.with(|rc| rc.borrow().is_none()) {
-                        log::debug!("EventLoop worker loop break");
-                        // drop all timeouts and intervals here
-                        TIMEOUTS.with(|rc| rc.borrow_mut().clear());
-                        INTERVALS.with(|rc| rc.borrow_mut().clear());
-                        // then do run_until_stalled again so finalizers may run
-                        pool.run_until_stalled();
-                        // exit loop
-                        break;
+                // wake futures which yielded so they continue in the next turn, don't wait for new tasks in that case
+                let yielded = YIELDED.with(|rc| std::mem::take(&mut *rc.borrow_mut()));
+                let woke = !yielded.is_empty();
+                if woke {
+                    for waker in yielded {
+                        waker.wake();
                     }
+                    next_deadline = Self::now();
                 }
-                log::debug!("EventLoop worker loop done");
-            })
-        });
 
-        Self {
-            tx,
-            join_handle: Some(join_handle),
-            id,
+                if let (ScheduleMode::Record(recording), Some(mut turn)) = (&mode, turn) {
+                    turn.woke = woke;
+                    // turns in which nothing happened are not recorded, except when they follow a turn which woke futures
+                    if turn.task || !turn.timers.is_empty() || turn.woke || prev_woke {
+                        recording.lock().push(turn);
+                    }
+                }
+                prev_woke = woke;
+
+                // shutdown indicator
+                if SPAWNER.with(|rc| rc.borrow().is_none()) {
+                    log::debug!("EventLoop worker loop break");
+                    // drop all timeouts and intervals here
+                    TIMEOUTS.with(|rc| rc.borrow_mut().clear());
+                    INTERVALS.with(|rc| rc.borrow_mut().clear());
+                    // then do run_until_stalled again so finalizers may run
+                    pool.run_until_stalled();
+                    // exit loop
+                    break;
+                }
+            }
+            log::debug!("EventLoop worker loop done");
+        })
+    }
+
+    /// get the current time of the EventLoop, this is the virtual time when the EventLoop is in replay mode
+    /// timeouts and intervals are scheduled based on this time
+    pub fn now() -> Instant {
+        VIRTUAL_NOW.with(|c| c.get()).unwrap_or_else(Instant::now)
+    }
+
+    /// get the turns recorded so far by an EventLoop in record mode
+    /// the recording may be passed to EventLoopBuilder::replay to reproduce the same ordering of tasks, timers and yields
+    /// (see RecordedTurn for what is not recorded)
+    pub fn recording(&self) -> Option<Vec<RecordedTurn>> {
+        self.inner.recording()
+    }
+
+    /// get info about this EventLoop
+    pub fn stats(&self) -> EventLoopStats {
+        self.inner.stats()
+    }
+
+    /// get a cloneable handle to this EventLoop, the EventLoop will keep running while the handle exists
+    pub fn handle(&self) -> EventLoopHandle {
+        EventLoopHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// get a weak handle to this EventLoop, the handle does not keep the EventLoop alive
+    pub fn weak_handle(&self) -> WeakEventLoopHandle {
+        WeakEventLoopHandle {
+            inner: Arc::downgrade(&self.inner),
         }
     }
 
     /// run scheduled tasks and calculate next deadline for running other tasks
-    fn run_timeouts_and_intervals() -> Instant {
+    /// the timers which fired are added to fired when recording
+    fn run_timeouts_and_intervals(mut fired: Option<&mut Vec<TimerEvent>>) -> Instant {
         // this is probably not very efficient when there are lots of timeouts, could be optimized by sorting based on next_run and thus not looping over future jobs
-        let now = Instant::now();
+        let now = Self::now();
 
         let timeout_todos = TIMEOUTS.with(|rc| {
             let timeouts = &mut rc.borrow_mut();
-            timeouts.remove_values(|timeout| timeout.next_run.lt(&now))
+            timeouts.remove_values(|timeout| timeout.next_run.le(&now))
         });
 
         for timeout_todo in timeout_todos {
+            if let Some(fired) = fired.as_mut() {
+                fired.push(TimerEvent::Timeout(timeout_todo.seq));
+            }
             let task = timeout_todo.task;
             task();
         }
@@ -129,25 +413,69 @@ impl EventLoop {
         let interval_todos = INTERVALS.with(|rc| {
             let intervals = &mut *rc.borrow_mut();
             let mut todos = vec![];
-            for interval in intervals.map.values_mut() {
-                if interval.next_run.lt(&now) {
-                    todos.push(interval.task.clone());
+            for interval in intervals.values_mut() {
+                if interval.next_run.le(&now) {
+                    todos.push((interval.seq, interval.task.clone()));
                     interval.next_run = now.add(interval.interval);
                 }
             }
             todos
         });
 
-        for interval_todo in interval_todos {
+        for (seq, interval_todo) in interval_todos {
+            if let Some(fired) = fired.as_mut() {
+                fired.push(TimerEvent::Interval(seq));
+            }
             interval_todo();
         }
 
         // next deadline is always calculated afterwards because timeouts may have been added from a timeout (or interval)
+        Self::next_timer_deadline(now)
+    }
+
+    /// fire the timers from a recorded turn and calculate next deadline
+    fn replay_timeouts_and_intervals(timers: &[TimerEvent]) -> Instant {
+        let now = Self::now();
+
+        for timer in timers {
+            match timer {
+                TimerEvent::Timeout(seq) => {
+                    let timeout = TIMEOUTS.with(|rc| {
+                        let timeouts = &mut rc.borrow_mut();
+                        timeouts.remove_values(|timeout| timeout.seq == *seq).pop()
+                    });
+                    match timeout {
+                        Some(timeout) => (timeout.task)(),
+                        None => log::error!("replay: timeout {} not found", seq),
+                    }
+                }
+                TimerEvent::Interval(seq) => {
+                    let task = INTERVALS.with(|rc| {
+                        let intervals = &mut *rc.borrow_mut();
+                        intervals
+                            .values_mut()
+                            .find(|interval| interval.seq == *seq)
+                            .map(|interval| {
+                                interval.next_run = now.add(interval.interval);
+                                interval.task.clone()
+                            })
+                    });
+                    match task {
+                        Some(task) => task(),
+                        None => log::error!("replay: interval {} not found", seq),
+                    }
+                }
+            }
+        }
 
+        Self::next_timer_deadline(now)
+    }
+
+    fn next_timer_deadline(now: Instant) -> Instant {
         let next_deadline = TIMEOUTS.with(|rc| {
             let timeouts = &mut rc.borrow();
             let mut ret = now.add(Duration::from_secs(10));
-            for timeout in timeouts.map.values() {
+            for timeout in timeouts.values() {
                 if timeout.next_run.lt(&ret) {
                     ret = timeout.next_run;
                 }
@@ -158,7 +486,7 @@ impl EventLoop {
         INTERVALS.with(|rc| {
             let intervals = &*rc.borrow();
             let mut ret = next_deadline;
-            for interval in intervals.map.values() {
+            for interval in intervals.values() {
                 if interval.next_run.lt(&ret) {
                     ret = interval.next_run;
                 }
@@ -167,12 +495,17 @@ impl EventLoop {
         })
     }
 
+    fn next_timer_seq() -> usize {
+        TIMER_SEQ.with(|c| {
+            let seq = c.get();
+            c.set(seq + 1);
+            seq
+        })
+    }
+
     /// internal method to ensure a member is called from the worker thread
     pub fn is_my_pool_thread(&self) -> bool {
-        LOCAL_ID.with(|rc| {
-            let opt = &*rc.borrow();
-            opt.is_some() && opt.unwrap() == self.id
-        })
+        self.inner.is_my_pool_thread()
     }
 
     /// internal method to ensure a member is called from the worker thread
@@ -208,6 +541,40 @@ impl EventLoop {
         })
     }
 
+    /// yield to the EventLoop from within a running future
+    /// the future continues in the next turn of the loop, after pending timeouts, intervals and queued tasks have been processed
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::eventloop::EventLoop;
+    /// use futures::executor::block_on;
+    /// let test_loop = EventLoop::new();
+    /// let fut = test_loop.add_future(async move {
+    ///     let mut total = 0;
+    ///     for x in 0..10 {
+    ///         total += x;
+    ///         EventLoop::yield_now().await;
+    ///     }
+    ///     total
+    /// });
+    /// assert_eq!(block_on(fut), 45);
+    /// ```
+    pub fn yield_now() -> impl Future<Output = ()> {
+        debug_assert!(EventLoop::is_a_pool_thread());
+        YieldNow { yielded: false }
+    }
+
+    /// yield to the EventLoop if the current turn of the loop has run longer than the turn budget
+    /// long running futures should call this regularly so timeouts and queued tasks are not starved
+    pub fn yield_if_over_budget() -> impl Future<Output = ()> {
+        debug_assert!(EventLoop::is_a_pool_thread());
+        let over_budget = TURN_START
+            .with(|c| c.get())
+            .is_some_and(|turn_start| turn_start.elapsed() >= TURN_BUDGET.with(|c| c.get()));
+        YieldNow {
+            yielded: !over_budget,
+        }
+    }
+
     /// add a task to the EventLoop from within a running task
     pub fn add_local_void<T: FnOnce() + 'static>(task: T) {
         debug_assert!(EventLoop::is_a_pool_thread());
@@ -219,18 +586,12 @@ impl EventLoop {
         &self,
         task: T,
     ) -> impl Future<Output = R> {
-        self.add_future(async move { task() })
+        self.inner.add(task)
     }
 
     /// execute a task in the EventLoop and block until it completes
     pub fn exe<R: Send + 'static, T: FnOnce() -> R + Send + 'static>(&self, task: T) -> R {
-        if Self::is_my_pool_thread(self) {
-            task()
-        } else {
-            let (tx, rx) = channel();
-            self.add_void(move || tx.send(task()).expect("could not send"));
-            rx.recv().expect("could not recv")
-        }
+        self.inner.exe(task)
     }
 
     /// add an async block to the EventLoop
@@ -250,12 +611,7 @@ impl EventLoop {
         &self,
         fut: F,
     ) -> impl Future<Output = R> {
-        let (tx, rx) = channel();
-        self.add_void(move || {
-            let res_fut = Self::add_local_future(fut);
-            tx.send(res_fut).expect("send failed");
-        });
-        rx.recv().expect("recv failed")
+        self.inner.add_future(fut)
     }
 
     /// add a Future to the pool, for when you don't need the result
@@ -276,38 +632,67 @@ impl EventLoop {
     /// assert_eq!(res, 1234);
     /// ```    
     pub fn add_future_void<F: Future<Output = ()> + Send + 'static>(&self, fut: F) {
-        self.add_void(move || EventLoop::add_local_future_void(fut))
+        self.inner.add_future_void(fut)
     }
 
     /// add a task to the pool
     pub fn add_void<T: FnOnce() + Send + 'static>(&self, task: T) {
-        if self.is_my_pool_thread() {
-            Self::add_local_void(task);
-        } else {
-            let tx = self.tx.clone();
-            tx.send(Box::new(task)).expect("send failed");
-        }
+        self.inner.add_void(task)
+    }
+
+    /// add a batch of tasks to the pool, the tasks are sent to the worker as a single message and run in one turn
+    /// # Example
+    /// ```rust
+    /// use hirofa_utils::eventloop::EventLoop;
+    /// use std::sync::mpsc::channel;
+    /// let test_loop = EventLoop::new();
+    /// let (tx, rx) = channel();
+    /// test_loop.add_batch((0..10).map(|x| {
+    ///     let tx = tx.clone();
+    ///     move || tx.send(x).expect("send failed")
+    /// }));
+    /// let res: Vec<i32> = rx.iter().take(10).collect();
+    /// assert_eq!(res, (0..10).collect::<Vec<i32>>());
+    /// ```
+    pub fn add_batch<I: IntoIterator<Item = T>, T: FnOnce() + Send + 'static>(&self, tasks: I) {
+        self.inner.add_batch(tasks)
     }
 
     /// add a timeout (delayed task) to the EventLoop
+    /// if there are already 2^20 pending timeouts an error is logged, the task is dropped and -1 is returned
     pub fn add_timeout<F: FnOnce() + 'static>(task: F, delay: Duration) -> i32 {
         debug_assert!(EventLoop::is_a_pool_thread());
         let timeout = Timeout {
-            next_run: Instant::now().add(delay),
+            seq: Self::next_timer_seq(),
+            next_run: Self::now().add(delay),
             task: Box::new(task),
         };
-        TIMEOUTS.with(|rc| rc.borrow_mut().insert(timeout) as i32)
+        TIMEOUTS.with(|rc| match rc.borrow_mut().try_insert(timeout) {
+            Ok(id) => id.id() as i32,
+            Err(_e) => {
+                log::error!("EventLoop: too many pending timeouts, timeout was dropped");
+                -1
+            }
+        })
     }
 
     /// add an interval (repeated task) to the EventLoop
+    /// if there are already 2^20 intervals an error is logged, the task is dropped and -1 is returned
     pub fn add_interval<F: Fn() + 'static>(task: F, delay: Duration, interval: Duration) -> i32 {
         debug_assert!(EventLoop::is_a_pool_thread());
         let interval = Interval {
-            next_run: Instant::now().add(delay),
+            seq: Self::next_timer_seq(),
+            next_run: Self::now().add(delay),
             interval,
             task: Rc::new(task),
         };
-        INTERVALS.with(|rc| rc.borrow_mut().insert(interval) as i32)
+        INTERVALS.with(|rc| match rc.borrow_mut().try_insert(interval) {
+            Ok(id) => id.id() as i32,
+            Err(_e) => {
+                log::error!("EventLoop: too many intervals, interval was dropped");
+                -1
+            }
+        })
     }
 
     /// cancel a previously added timeout
@@ -315,9 +700,7 @@ impl EventLoop {
         debug_assert!(EventLoop::is_a_pool_thread());
         TIMEOUTS.with(|rc| {
             let map = &mut *rc.borrow_mut();
-            if map.contains_key(&(id as usize)) {
-                let _ = map.remove(&(id as usize));
-            }
+            let _ = map.remove_opt(&TimeoutId::from_id(id as usize));
         });
     }
 
@@ -326,20 +709,142 @@ impl EventLoop {
         debug_assert!(EventLoop::is_a_pool_thread());
         INTERVALS.with(|rc| {
             let map = &mut *rc.borrow_mut();
-            if map.contains_key(&(id as usize)) {
-                let _ = map.remove(&(id as usize));
-            }
+            let _ = map.remove_opt(&IntervalId::from_id(id as usize));
         });
     }
 }
 
+impl EventLoopBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// pin the worker thread to these cpu cores (linux only)
+    pub fn cpu_affinity(mut self, cores: Vec<usize>) -> Self {
+        self.cpu_affinity = Some(cores);
+        self
+    }
+
+    /// set the niceness of the worker thread (linux only)
+    pub fn niceness(mut self, niceness: i32) -> Self {
+        self.niceness = Some(niceness);
+        self
+    }
+
+    /// set the time a turn of the loop may take before EventLoop::yield_if_over_budget yields, defaults to 10ms
+    pub fn turn_budget(mut self, turn_budget: Duration) -> Self {
+        self.turn_budget = Some(turn_budget);
+        self
+    }
+
+    /// record the turns of the EventLoop, the recording can be retrieved with EventLoop::recording
+    pub fn record(mut self) -> Self {
+        self.record = true;
+        self
+    }
+
+    /// replay a recording made by an EventLoop in record mode
+    /// the EventLoop runs on a virtual clock and follows the recorded turns, so tasks and timers run in the recorded order
+    /// regardless of wall clock timing. Tasks must be submitted in the same order as when recording, the EventLoop waits
+    /// for them when a turn in the recording received a task. Once the recording is exhausted the EventLoop keeps
+    /// running on the virtual clock, which then advances at the pace of the wall clock
+    /// please note that EventLoop::yield_if_over_budget depends on wall clock time, use EventLoop::yield_now for deterministic yields
+    /// and that wakes from other threads are not recorded, futures which await work done in another thread are only
+    /// deterministic if that work reports back by submitting a task to the EventLoop
+    pub fn replay(mut self, recording: Vec<RecordedTurn>) -> Self {
+        self.replay = Some(recording);
+        self
+    }
+
+    /// start the EventLoop
+    pub fn build(self) -> EventLoop {
+        EventLoop::new_from_builder(self)
+    }
+}
+
 impl Default for EventLoop {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Drop for EventLoop {
+impl EventLoopInner {
+    fn recording(&self) -> Option<Vec<RecordedTurn>> {
+        self.recording
+            .as_ref()
+            .map(|recording| recording.lock().clone())
+    }
+
+    fn stats(&self) -> EventLoopStats {
+        EventLoopStats {
+            id: self.id,
+            cpu_affinity: self.cpu_affinity.clone(),
+            niceness: self.niceness,
+        }
+    }
+
+    fn is_my_pool_thread(&self) -> bool {
+        LOCAL_ID.with(|rc| {
+            let opt = &*rc.borrow();
+            opt.is_some() && opt.unwrap() == self.id
+        })
+    }
+
+    fn add<T: FnOnce() -> R + Send + 'static, R: Send + 'static>(
+        &self,
+        task: T,
+    ) -> impl Future<Output = R> {
+        self.add_future(async move { task() })
+    }
+
+    fn exe<R: Send + 'static, T: FnOnce() -> R + Send + 'static>(&self, task: T) -> R {
+        if self.is_my_pool_thread() {
+            task()
+        } else {
+            let (tx, rx) = channel();
+            self.add_void(move || tx.send(task()).expect("could not send"));
+            rx.recv().expect("could not recv")
+        }
+    }
+
+    fn add_future<R: Send + 'static, F: Future<Output = R> + Send + 'static>(
+        &self,
+        fut: F,
+    ) -> impl Future<Output = R> {
+        let (tx, rx) = channel();
+        self.add_void(move || {
+            let res_fut = EventLoop::add_local_future(fut);
+            tx.send(res_fut).expect("send failed");
+        });
+        rx.recv().expect("recv failed")
+    }
+
+    fn add_future_void<F: Future<Output = ()> + Send + 'static>(&self, fut: F) {
+        self.add_void(move || EventLoop::add_local_future_void(fut))
+    }
+
+    fn add_batch<I: IntoIterator<Item = T>, T: FnOnce() + Send + 'static>(&self, tasks: I) {
+        let tasks: Vec<T> = tasks.into_iter().collect();
+        if !tasks.is_empty() {
+            self.add_void(move || {
+                for task in tasks {
+                    task();
+                }
+            });
+        }
+    }
+
+    fn add_void<T: FnOnce() + Send + 'static>(&self, task: T) {
+        if self.is_my_pool_thread() {
+            EventLoop::add_local_void(task);
+        } else {
+            let tx = self.tx.clone();
+            tx.send(Box::new(task)).expect("send failed");
+        }
+    }
+}
+
+impl Drop for EventLoopInner {
     fn drop(&mut self) {
         self.exe(|| {
             SPAWNER.with(|rc| {
@@ -348,19 +853,163 @@ impl Drop for EventLoop {
             })
         });
         if let Some(join_handle) = self.join_handle.take() {
-            let _ = join_handle.join();
+            if self.is_my_pool_thread() {
+                // the last owner was dropped from within a task in the loop, joining here would deadlock
+                // the worker exits by itself after the current task because the spawner was removed
+                log::debug!("EventLoop dropped from worker thread, not joining");
+            } else {
+                let _ = join_handle.join();
+            }
         }
     }
 }
+
 impl std::fmt::Debug for EventLoop {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("EventLoop")
     }
 }
 
+impl EventLoopHandle {
+    /// get a weak handle to the EventLoop
+    pub fn downgrade(&self) -> WeakEventLoopHandle {
+        WeakEventLoopHandle {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// get info about the EventLoop
+    pub fn stats(&self) -> EventLoopStats {
+        self.inner.stats()
+    }
+
+    /// see EventLoop::recording
+    pub fn recording(&self) -> Option<Vec<RecordedTurn>> {
+        self.inner.recording()
+    }
+
+    /// see EventLoop::is_my_pool_thread
+    pub fn is_my_pool_thread(&self) -> bool {
+        self.inner.is_my_pool_thread()
+    }
+
+    /// add a task to the EventLoop
+    pub fn add<T: FnOnce() -> R + Send + 'static, R: Send + 'static>(
+        &self,
+        task: T,
+    ) -> impl Future<Output = R> {
+        self.inner.add(task)
+    }
+
+    /// execute a task in the EventLoop and block until it completes
+    pub fn exe<R: Send + 'static, T: FnOnce() -> R + Send + 'static>(&self, task: T) -> R {
+        self.inner.exe(task)
+    }
+
+    /// add an async block to the EventLoop
+    pub fn add_future<R: Send + 'static, F: Future<Output = R> + Send + 'static>(
+        &self,
+        fut: F,
+    ) -> impl Future<Output = R> {
+        self.inner.add_future(fut)
+    }
+
+    /// add a Future to the EventLoop, for when you don't need the result
+    pub fn add_future_void<F: Future<Output = ()> + Send + 'static>(&self, fut: F) {
+        self.inner.add_future_void(fut)
+    }
+
+    /// add a task to the EventLoop
+    pub fn add_void<T: FnOnce() + Send + 'static>(&self, task: T) {
+        self.inner.add_void(task)
+    }
+
+    /// add a batch of tasks to the EventLoop, see EventLoop::add_batch
+    pub fn add_batch<I: IntoIterator<Item = T>, T: FnOnce() + Send + 'static>(&self, tasks: I) {
+        self.inner.add_batch(tasks)
+    }
+}
+
+impl std::fmt::Debug for EventLoopHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventLoopHandle")
+    }
+}
+
+impl WeakEventLoopHandle {
+    /// get a strong handle if the EventLoop is still alive
+    pub fn upgrade(&self) -> Option<EventLoopHandle> {
+        self.inner.upgrade().map(|inner| EventLoopHandle { inner })
+    }
+
+    /// check if the EventLoop is still alive
+    pub fn is_alive(&self) -> bool {
+        self.inner.strong_count() > 0
+    }
+
+    fn upgrade_or_err(&self) -> anyhow::Result<EventLoopHandle> {
+        self.upgrade()
+            .ok_or_else(|| anyhow::anyhow!("EventLoop was dropped"))
+    }
+
+    /// add a task to the EventLoop, fails if the EventLoop was dropped
+    pub fn add<T: FnOnce() -> R + Send + 'static, R: Send + 'static>(
+        &self,
+        task: T,
+    ) -> anyhow::Result<impl Future<Output = R>> {
+        Ok(self.upgrade_or_err()?.add(task))
+    }
+
+    /// execute a task in the EventLoop and block until it completes, fails if the EventLoop was dropped
+    pub fn exe<R: Send + 'static, T: FnOnce() -> R + Send + 'static>(
+        &self,
+        task: T,
+    ) -> anyhow::Result<R> {
+        Ok(self.upgrade_or_err()?.exe(task))
+    }
+
+    /// add an async block to the EventLoop, fails if the EventLoop was dropped
+    pub fn add_future<R: Send + 'static, F: Future<Output = R> + Send + 'static>(
+        &self,
+        fut: F,
+    ) -> anyhow::Result<impl Future<Output = R>> {
+        Ok(self.upgrade_or_err()?.add_future(fut))
+    }
+
+    /// add a Future to the EventLoop, fails if the EventLoop was dropped
+    pub fn add_future_void<F: Future<Output = ()> + Send + 'static>(
+        &self,
+        fut: F,
+    ) -> anyhow::Result<()> {
+        self.upgrade_or_err()?.add_future_void(fut);
+        Ok(())
+    }
+
+    /// add a task to the EventLoop, fails if the EventLoop was dropped
+    pub fn add_void<T: FnOnce() + Send + 'static>(&self, task: T) -> anyhow::Result<()> {
+        self.upgrade_or_err()?.add_void(task);
+        Ok(())
+    }
+
+    /// add a batch of tasks to the EventLoop, fails if the EventLoop was dropped
+    pub fn add_batch<I: IntoIterator<Item = T>, T: FnOnce() + Send + 'static>(
+        &self,
+        tasks: I,
+    ) -> anyhow::Result<()> {
+        self.upgrade_or_err()?.add_batch(tasks);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for WeakEventLoopHandle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WeakEventLoopHandle")
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::eventloop::EventLoop;
+    use crate::eventloop::{EventLoop, MAX_TIMERS};
     use futures::executor::block_on;
     use std::ops::Add;
     use std::sync::mpsc::channel;
@@ -397,7 +1046,7 @@ pub mod tests {
         test_loop.add_void(move || {
             EventLoop::add_timeout(
                 move || {
-                    tx.send(129).ok().expect("send failed");
+                    tx.send(129).expect("send failed");
                 },
                 Duration::from_secs(2),
             );
@@ -418,10 +1067,172 @@ pub mod tests {
         log::debug!("after loop dropped");
     }
 
+    #[test]
+    fn test_handles() {
+        let test_loop = EventLoop::new();
+        let handle = test_loop.handle();
+        let weak = test_loop.weak_handle();
+
+        assert_eq!(weak.exe(|| 1).expect("loop gone"), 1);
+
+        // the handle keeps the loop alive
+        drop(test_loop);
+        assert_eq!(handle.exe(|| 2), 2);
+        assert!(weak.is_alive());
+
+        // drop the last strong handle from within the loop itself
+        let (tx, rx) = channel();
+        let h2 = handle.clone();
+        drop(handle);
+        h2.clone().add_void(move || {
+            drop(h2);
+            tx.send(()).expect("send failed");
+        });
+        rx.recv().expect("recv failed");
+
+        // wait for the worker to drop the handle
+        let start = Instant::now();
+        while weak.is_alive() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!weak.is_alive());
+        assert!(weak.upgrade().is_none());
+        assert!(weak.add_void(|| {}).is_err());
+        assert!(weak.exe(|| 3).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_affinity() {
+        use crate::affinity::{current_thread_affinity, current_thread_niceness};
+        use crate::eventloop::EventLoopBuilder;
+
+        let core = current_thread_affinity().expect("no affinity")[0];
+        // raising the niceness is always allowed
+        let niceness = current_thread_niceness().expect("no niceness").max(5);
+        let test_loop = EventLoopBuilder::new()
+            .cpu_affinity(vec![core])
+            .niceness(niceness)
+            .build();
+        let stats = test_loop.stats();
+        assert_eq!(stats.cpu_affinity, Some(vec![core]));
+        assert_eq!(stats.niceness, Some(niceness));
+        // verify with sched_getaffinity from within the worker
+        assert_eq!(test_loop.exe(current_thread_affinity), Some(vec![core]));
+    }
+
+    #[test]
+    fn test_timer_limit() {
+        let test_loop = EventLoop::new();
+        let (rejected_id, id_after_clear) = test_loop.exe(|| {
+            let mut last_id = 0;
+            for _ in 0..MAX_TIMERS {
+                last_id = EventLoop::add_timeout(|| {}, Duration::from_secs(60));
+            }
+            let rejected_id = EventLoop::add_timeout(|| {}, Duration::from_secs(60));
+            // clearing a timeout makes room again
+            EventLoop::clear_timeout(last_id);
+            let id_after_clear = EventLoop::add_timeout(|| {}, Duration::from_secs(60));
+            (rejected_id, id_after_clear)
+        });
+        assert_eq!(rejected_id, -1);
+        assert!(id_after_clear >= 0);
+    }
+
+    #[test]
+    fn test_yield() {
+        let test_loop = EventLoop::builder()
+            .turn_budget(Duration::from_millis(2))
+            .build();
+
+        let (tx, rx) = channel();
+        test_loop.add_void(move || {
+            let start = Instant::now();
+            EventLoop::add_timeout(
+                move || {
+                    tx.send(start.elapsed()).expect("send failed");
+                },
+                Duration::from_millis(10),
+            );
+        });
+        // a busy future which runs for 500ms
+        let busy = test_loop.add_future(async move {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(500) {
+                std::thread::sleep(Duration::from_millis(1));
+                EventLoop::yield_if_over_budget().await;
+            }
+        });
+
+        let delay = rx.recv().expect("recv failed");
+        assert!(delay >= Duration::from_millis(10));
+        assert!(delay < Duration::from_millis(100), "delay was {:?}", delay);
+        block_on(busy);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        use parking_lot::Mutex;
+        use std::sync::Arc;
+
+        fn run(event_loop: &EventLoop, sleep: Duration) -> Vec<&'static str> {
+            let log = Arc::new(Mutex::new(vec![]));
+            let l = log.clone();
+            event_loop.add_void(move || {
+                let l1 = l.clone();
+                EventLoop::add_timeout(move || l1.lock().push("t50"), Duration::from_millis(50));
+                let l2 = l.clone();
+                EventLoop::add_timeout(move || l2.lock().push("t150"), Duration::from_millis(150));
+                l.lock().push("a");
+            });
+            std::thread::sleep(sleep);
+            let l = log.clone();
+            event_loop.add_void(move || l.lock().push("b"));
+            std::thread::sleep(sleep);
+            let l = log.clone();
+            event_loop.exe(move || l.lock().push("c"));
+            let res = log.lock().clone();
+            res
+        }
+
+        let recording_loop = EventLoop::builder().record().build();
+        let recorded = run(&recording_loop, Duration::from_millis(100));
+        assert_eq!(recorded, vec!["a", "t50", "b", "t150", "c"]);
+        let recording = recording_loop.recording().expect("no recording");
+        drop(recording_loop);
+
+        // without sleeping a normal loop would run b and c before the timeouts
+        let replay_loop = EventLoop::builder().replay(recording).build();
+        let replayed = run(&replay_loop, Duration::from_millis(0));
+        assert_eq!(recorded, replayed);
+
+        // after the recording the virtual clock advances at the pace of the wall clock
+        let (tx, rx) = channel();
+        let fired = Arc::new(Mutex::new(0));
+        let f = fired.clone();
+        replay_loop.add_void(move || {
+            EventLoop::add_interval(
+                move || *f.lock() += 1,
+                Duration::from_millis(50),
+                Duration::from_millis(50),
+            );
+            EventLoop::add_timeout(
+                move || tx.send(()).expect("send failed"),
+                Duration::from_millis(200),
+            );
+        });
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("timeout did not fire");
+        let fired = *fired.lock();
+        assert!((2..=5).contains(&fired), "interval fired {} times", fired);
+    }
+
     #[test]
     fn test_sync() {
         fn t<E: Send + Sync>(_s: E) {}
         let event_loop = EventLoop::new();
+        t(event_loop.handle());
+        t(event_loop.weak_handle());
         t(event_loop);
         println!("yup, EL is sync");
     }
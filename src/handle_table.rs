@@ -0,0 +1,175 @@
+use crate::auto_id_map::{AutoIdMap, InsertError, Key};
+
+/// HandleTable is an AutoIdMap where every id is reference counted
+///
+/// an inserted value starts with a refcount of 1, retain increments and release decrements the refcount, when the count
+/// reaches zero the value is removed from the table
+/// when the table is dropped while there are still live handles these are reported as leaks in the log
+/// # Example
+/// ```rust
+/// use hirofa_utils::handle_table::HandleTable;
+/// let mut table = HandleTable::new("promises");
+/// let id = table.insert("promise");
+/// assert!(table.retain(&id));
+/// assert_eq!(table.release(&id), None);
+/// assert_eq!(table.release(&id), Some("promise"));
+/// assert!(table.get(&id).is_none());
+/// ```
+pub struct HandleTable<T, K: Key = usize> {
+    name: &'static str,
+    map: AutoIdMap<HandleEntry<T>, K>,
+}
+
+struct HandleEntry<T> {
+    refcount: usize,
+    value: T,
+}
+
+impl<T> HandleTable<T> {
+    /// create a new HandleTable, the name is used when reporting leaks
+    pub fn new(name: &'static str) -> Self {
+        Self::new_keyed(name)
+    }
+
+    /// create a new HandleTable with a maximum number of live handles
+    pub fn new_with_max_size(name: &'static str, max_size: usize) -> Self {
+        Self::new_keyed_with_max_size(name, max_size)
+    }
+}
+
+impl<T, K: Key> HandleTable<T, K> {
+    /// create a new HandleTable with a custom key type
+    pub fn new_keyed(name: &'static str) -> Self {
+        Self::new_keyed_with_max_size(name, usize::MAX)
+    }
+
+    /// create a new HandleTable with a custom key type and a maximum number of live handles
+    pub fn new_keyed_with_max_size(name: &'static str, max_size: usize) -> Self {
+        Self {
+            name,
+            map: AutoIdMap::new_keyed_with_max_size(max_size),
+        }
+    }
+
+    /// insert a value with a refcount of 1 and return its id, this will panic if the table is full
+    pub fn insert(&mut self, value: T) -> K {
+        self.try_insert(value).expect("HandleTable is full")
+    }
+
+    /// insert a value with a refcount of 1 and return its id
+    pub fn try_insert(&mut self, value: T) -> Result<K, InsertError<T>> {
+        self.map
+            .try_insert(HandleEntry { refcount: 1, value })
+            .map_err(|e| InsertError::new(e.into_inner().value))
+    }
+
+    /// get a value based on its id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn get(&self, id: &K) -> Option<&T> {
+        self.map.get(id).map(|entry| &entry.value)
+    }
+
+    /// get a value based on its id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn get_mut(&mut self, id: &K) -> Option<&mut T> {
+        self.map.get_mut(id).map(|entry| &mut entry.value)
+    }
+
+    /// increment the refcount of a handle, returns false if there is no such handle
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn retain(&mut self, id: &K) -> bool {
+        match self.map.get_mut(id) {
+            Some(entry) => {
+                entry.refcount += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// decrement the refcount of a handle, when it reaches zero the value is removed and returned
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn release(&mut self, id: &K) -> Option<T> {
+        let entry = self.map.get_mut(id)?;
+        entry.refcount -= 1;
+        if entry.refcount == 0 {
+            self.map.remove_opt(id).map(|entry| entry.value)
+        } else {
+            None
+        }
+    }
+
+    /// get the refcount of a handle
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn refcount(&self, id: &K) -> Option<usize> {
+        self.map.get(id).map(|entry| entry.refcount)
+    }
+
+    /// get the ids and refcounts of all live handles
+    pub fn live_handles(&self) -> Vec<(K, usize)> {
+        self.map
+            .iter()
+            .map(|(id, entry)| (id, entry.refcount))
+            .collect()
+    }
+
+    /// check if a table contains a certain id
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn contains_key(&self, id: &K) -> bool {
+        self.map.contains_key(id)
+    }
+
+    /// get the number of live handles
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// see if there are no live handles
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<T, K: Key> Drop for HandleTable<T, K> {
+    fn drop(&mut self) {
+        if !self.map.is_empty() {
+            log::warn!(
+                "HandleTable {} dropped with {} live handles (id, refcount): {:?}",
+                self.name,
+                self.map.len(),
+                self.live_handles()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::handle_table::HandleTable;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_handle_table() {
+        let value = Rc::new(());
+        let mut table = HandleTable::new("test");
+        let id = table.insert(value.clone());
+        assert_eq!(Rc::strong_count(&value), 2);
+
+        assert!(table.retain(&id));
+        assert!(table.retain(&id));
+        assert_eq!(table.refcount(&id), Some(3));
+
+        assert!(table.release(&id).is_none());
+        assert!(table.release(&id).is_none());
+        assert_eq!(table.live_handles(), vec![(id, 1)]);
+
+        // the last release removes the value, dropping it
+        drop(table.release(&id));
+        assert_eq!(Rc::strong_count(&value), 1);
+        assert!(table.is_empty());
+
+        // stale ids are ignored
+        assert!(!table.retain(&id));
+        assert!(table.release(&id).is_none());
+    }
+}
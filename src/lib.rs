@@ -1,6 +1,11 @@
+pub mod affinity;
+pub mod async_cache;
 pub mod auto_id_map;
 pub mod cache;
+pub mod concurrent_auto_id_map;
 pub mod debug_mutex;
 pub mod eventloop;
+pub mod handle_table;
 pub mod resolvable_future;
+pub mod shared_cache;
 pub mod task_manager;
@@ -0,0 +1,207 @@
+use crate::cache::{Cache, CacheIFace};
+use crate::concurrent_auto_id_map::shard_bits_for_max_size;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+/// SharedCache is a thread safe Cache which may be shared between threads
+///
+/// keys are hashed to a shard with its own Mutex
+/// the producer runs without holding a lock, so a slow producer does not block gets for other keys
+/// if a key in the same shard is inserted or invalidated while the producer runs, the produced item is returned but not
+/// cached, so an invalidated key is never refilled with a stale item
+/// please note that max_size is divided over the shards, so the cache may start evicting before it holds max_size
+/// entries when the keys are not evenly distributed
+/// # Example
+/// ```rust
+/// use hirofa_utils::shared_cache::SharedCache;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// let cache = Arc::new(SharedCache::new(
+///     |key: &i32| Some(key * 2),
+///     Duration::from_secs(60),
+///     100,
+/// ));
+/// let c = cache.clone();
+/// let item = std::thread::spawn(move || c.get(&21)).join().unwrap();
+/// assert_eq!(item, Some(42));
+/// assert!(cache.contains_key(&21));
+/// ```
+pub struct SharedCache<K: Eq + Hash, O> {
+    #[allow(clippy::type_complexity)]
+    producer: Box<dyn Fn(&K) -> Option<O> + Send + Sync>,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard<K, O>>>,
+}
+
+struct Shard<K: Eq + Hash, O> {
+    cache: Cache<K, O>,
+    // incremented on every insert or invalidate, a get only caches the produced item if this did not change
+    epoch: u64,
+}
+
+impl<K: Eq + Hash + Clone, O: Clone> SharedCache<K, O> {
+    pub fn new<P>(producer: P, max_inactive_time: Duration, max_size: usize) -> Self
+    where
+        P: Fn(&K) -> Option<O> + Send + Sync + 'static,
+    {
+        let shard_count = 1 << shard_bits_for_max_size(max_size);
+        let shard_max_size = max_size.div_ceil(shard_count);
+        let shards = (0..shard_count)
+            .map(|_| {
+                Mutex::new(Shard {
+                    cache: Cache::new(|_key: &K| None, max_inactive_time, shard_max_size),
+                    epoch: 0,
+                })
+            })
+            .collect();
+        Self {
+            producer: Box::new(producer),
+            hasher: RandomState::new(),
+            shards,
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K, O>> {
+        let idx = self.hasher.hash_one(key) as usize & (self.shards.len() - 1);
+        &self.shards[idx]
+    }
+
+    /// get an item, if it is not cached the producer is called and the result is cached
+    pub fn get(&self, key: &K) -> Option<O> {
+        let shard = self.shard(key);
+        let epoch = {
+            let shard = &mut *shard.lock();
+            shard.cache.invalidate_stale();
+            if let Some(item) = shard.cache.opt(key) {
+                return Some(item.clone());
+            }
+            shard.epoch
+        };
+        let item = (self.producer)(key)?;
+        let shard = &mut *shard.lock();
+        if shard.epoch == epoch {
+            shard.cache.insert(key.clone(), item.clone());
+        }
+        Some(item)
+    }
+
+    /// get an item if it is cached, this never calls the producer
+    pub fn opt(&self, key: &K) -> Option<O> {
+        self.shard(key).lock().cache.opt(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).lock().cache.contains_key(key)
+    }
+
+    pub fn insert(&self, key: K, item: O) {
+        let shard = &mut *self.shard(&key).lock();
+        shard.epoch += 1;
+        shard.cache.insert(key, item);
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        let shard = &mut *self.shard(key).lock();
+        shard.epoch += 1;
+        shard.cache.invalidate(key);
+    }
+
+    pub fn invalidate_all(&self) {
+        for shard in &self.shards {
+            let shard = &mut *shard.lock();
+            shard.epoch += 1;
+            shard.cache.invalidate_all();
+        }
+    }
+
+    pub fn invalidate_stale(&self) {
+        for shard in &self.shards {
+            shard.lock().cache.invalidate_stale();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().cache.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.lock().cache.is_empty())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::shared_cache::SharedCache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_shared_cache() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let producer_loads = loads.clone();
+        let producer = move |key: &usize| {
+            producer_loads.fetch_add(1, Ordering::SeqCst);
+            Some(format!("entry: {key}"))
+        };
+        let cache = Arc::new(SharedCache::new(producer, Duration::from_secs(60), 1000));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_t| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for x in 0..100 {
+                        assert_eq!(cache.get(&x), Some(format!("entry: {x}")));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(cache.len(), 100);
+        // concurrent misses may both call the producer, but every key is loaded at least once
+        assert!(loads.load(Ordering::SeqCst) >= 100);
+
+        let loaded = loads.load(Ordering::SeqCst);
+        assert_eq!(cache.get(&5).as_deref(), Some("entry: 5"));
+        assert_eq!(loads.load(Ordering::SeqCst), loaded);
+
+        cache.invalidate(&5);
+        assert!(!cache.contains_key(&5));
+        assert!(cache.opt(&5).is_none());
+        cache.invalidate_all();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_shared_cache_invalidate_during_load() {
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (resume_tx, resume_rx) = std::sync::mpsc::channel::<()>();
+        let resume_rx = std::sync::Mutex::new(resume_rx);
+        let producer = move |key: &usize| {
+            started_tx.send(()).unwrap();
+            resume_rx.lock().unwrap().recv().unwrap();
+            Some(format!("stale: {key}"))
+        };
+        let cache = Arc::new(SharedCache::new(producer, Duration::from_secs(60), 10));
+
+        let c = cache.clone();
+        let handle = std::thread::spawn(move || c.get(&1));
+        started_rx.recv().unwrap();
+        cache.invalidate(&1);
+        resume_tx.send(()).unwrap();
+
+        // the get returns the produced item but it was invalidated while loading so it is not cached
+        assert_eq!(handle.join().unwrap().as_deref(), Some("stale: 1"));
+        assert!(!cache.contains_key(&1));
+    }
+}
@@ -1,5 +1,7 @@
+use crate::affinity;
 use futures::Future;
 use log::trace;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::runtime::Runtime;
 use tokio::task::JoinError;
 
@@ -7,18 +9,68 @@ pub struct TaskManager {
     runtime: Runtime,
 }
 
-impl TaskManager {
+/// builder for a TaskManager with custom worker thread settings
+pub struct TaskManagerBuilder {
+    thread_count: usize,
+    cpu_affinity: Option<Vec<usize>>,
+    niceness: Option<i32>,
+}
+
+impl TaskManagerBuilder {
     pub fn new(thread_count: usize) -> Self {
+        Self {
+            thread_count,
+            cpu_affinity: None,
+            niceness: None,
+        }
+    }
+
+    /// pin the worker threads to these cpu cores (linux only)
+    /// every thread is pinned to a single core, cores are assigned round-robin as threads are started
+    pub fn cpu_affinity(mut self, cores: Vec<usize>) -> Self {
+        self.cpu_affinity = Some(cores);
+        self
+    }
+
+    /// set the niceness of the worker threads (linux only)
+    pub fn niceness(mut self, niceness: i32) -> Self {
+        self.niceness = Some(niceness);
+        self
+    }
+
+    pub fn build(self) -> TaskManager {
         // start threads
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all().max_blocking_threads(self.thread_count);
+
+        if self.cpu_affinity.is_some() || self.niceness.is_some() {
+            let cpu_affinity = self.cpu_affinity.filter(|cores| !cores.is_empty());
+            let niceness = self.niceness;
+            let thread_counter = AtomicUsize::new(0);
+            builder.on_thread_start(move || {
+                let core = cpu_affinity.as_ref().map(|cores| {
+                    let idx = thread_counter.fetch_add(1, Ordering::Relaxed);
+                    [cores[idx % cores.len()]]
+                });
+                affinity::apply_to_current_thread(core.as_ref().map(|c| &c[..]), niceness);
+            });
+        }
 
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .max_blocking_threads(thread_count)
-            .build()
-            .expect("tokio rt failed");
+        let runtime = builder.build().expect("tokio rt failed");
 
         TaskManager { runtime }
     }
+}
+
+impl TaskManager {
+    pub fn new(thread_count: usize) -> Self {
+        TaskManagerBuilder::new(thread_count).build()
+    }
+
+    /// create a builder for a TaskManager with custom settings
+    pub fn builder(thread_count: usize) -> TaskManagerBuilder {
+        TaskManagerBuilder::new(thread_count)
+    }
 
     pub fn add_task<T: FnOnce() + Send + 'static>(&self, task: T) {
         trace!("adding a task");
@@ -85,4 +137,18 @@ mod tests {
             assert_eq!(s, "res");
         }
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_affinity() {
+        use crate::affinity::current_thread_affinity;
+        use crate::task_manager::TaskManagerBuilder;
+
+        let core = current_thread_affinity().expect("no affinity")[0];
+        let tm = TaskManagerBuilder::new(2).cpu_affinity(vec![core]).build();
+        for _x in 0..4 {
+            let affinity = tm.run_task_blocking(current_thread_affinity);
+            assert_eq!(affinity, Some(vec![core]));
+        }
+    }
 }